@@ -0,0 +1,42 @@
+// Measures the memory a heap of small objects takes from the system allocator
+// for each object, beyond the data of the object itself.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+use shifgrethor::{collect, letroot, GcStore};
+
+const OBJECTS: usize = 1_000_000;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() {
+    letroot!(root);
+    let before = ALLOCATED.load(SeqCst);
+    let objects = root.gc((0..OBJECTS).map(GcStore::new).collect::<Vec<_>>());
+    collect();
+
+    // The vector of stores which keeps the objects alive is not counted
+    let stores = objects.capacity() * mem::size_of::<GcStore<usize>>();
+    let bytes = ALLOCATED.load(SeqCst) - before - stores;
+    let overhead = bytes as f64 / OBJECTS as f64 - mem::size_of::<usize>() as f64;
+    println!("{} objects of {} bytes: {:.1} bytes of overhead each", OBJECTS, mem::size_of::<usize>(), overhead);
+}
@@ -0,0 +1,36 @@
+// Times the sweeps of a heap in which most objects survive. The heap is swept
+// lazily, so that sweeping can be timed apart from marking.
+
+use std::time::{Duration, Instant};
+
+use shifgrethor::{collect, letroot, sweep_step, GcConfig, GcStore};
+
+const LIVE: u64 = 1_000_000;
+const COLLECTIONS: u32 = 20;
+
+fn main() {
+    GcConfig::new().lazy_sweep(1).install();
+
+    letroot!(root);
+    let _live = root.gc((0..LIVE).map(GcStore::new).collect::<Vec<_>>());
+    collect();
+    sweep_step(usize::MAX);
+
+    let mut marking = Duration::default();
+    let mut sweeping = Duration::default();
+    for _ in 0..COLLECTIONS {
+        for i in 0..LIVE / 10 {
+            letroot!(temp);
+            temp.gc(i);
+        }
+        let start = Instant::now();
+        collect();
+        marking += start.elapsed();
+
+        let start = Instant::now();
+        sweep_step(usize::MAX);
+        sweeping += start.elapsed();
+    }
+
+    println!("{} live objects: marked in {:?}, swept in {:?}", LIVE, marking / COLLECTIONS, sweeping / COLLECTIONS);
+}
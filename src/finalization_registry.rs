@@ -0,0 +1,41 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use gc::Trace;
+
+use crate::Gc;
+
+/// Calls a function with a held value after each object registered with it
+/// has been collected
+///
+/// Unlike `#[gc(finalize)]`, this works for objects of any type. The callback
+/// is run along with the finalizers of the collection which finds the object
+/// dead, unless the registry has been dropped by then.
+pub struct FinalizationRegistry<H> {
+    callback: Rc<RefCell<dyn FnMut(H)>>,
+}
+
+impl<H: 'static> FinalizationRegistry<H> {
+    pub fn new<F: FnMut(H) + 'static>(callback: F) -> FinalizationRegistry<H> {
+        FinalizationRegistry { callback: Rc::new(RefCell::new(callback)) }
+    }
+
+    /// Call the callback with `held` once the target has been collected
+    ///
+    /// The held value is not traced. If it roots the target, the target will
+    /// never be collected.
+    ///
+    /// Panics if the target is managed by a `SyncHeap`, because the callback
+    /// could then be run on any thread which joins the heap.
+    pub fn register<T: Trace + ?Sized>(&self, target: Gc<'_, T>, held: H) {
+        let callback = Rc::downgrade(&self.callback);
+        let cleanup = Box::new(move || {
+            if let Some(callback) = callback.upgrade() {
+                (&mut *callback.borrow_mut())(held)
+            }
+        });
+        unsafe {
+            gc::on_collect(Gc::raw(target), cleanup)
+        }
+    }
+}
@@ -11,6 +11,12 @@ pub struct Gc<'root, T: ?Sized + 'root> {
     _marker: PhantomData<(&'root T, PhantomPinned)>,
 }
 
+// A Gc is a shared reference to rooted data. The collector only writes to the
+// headers of reachable objects, which are atomic, and another thread can only
+// reach a heap's state if it has joined the heap as a mutator.
+unsafe impl<'root, T: Sync + ?Sized> Send for Gc<'root, T> { }
+unsafe impl<'root, T: Sync + ?Sized> Sync for Gc<'root, T> { }
+
 impl<'root, T: ?Sized> Clone for Gc<'root, T> {
     fn clone(&self) -> Gc<'root, T> {
         *self
@@ -23,6 +29,8 @@ unsafe impl<'root, T: Trace + ?Sized> Trace for Gc<'root, T> {
     unsafe fn manage(&self) { }
 
     unsafe fn finalize(&mut self) { }
+
+    unsafe fn relocate(&mut self) { }
 }
 
 impl<'root, T: ?Sized> Gc<'root, T> {
@@ -32,9 +40,10 @@ impl<'root, T: ?Sized> Gc<'root, T> {
         }
     }
 
-    // NOTE: Problematic for copying collectors
+    /// Pin this object, so that it will never be moved by `compact`.
     pub fn pin(self) -> Pin<Gc<'root, T>> {
         unsafe {
+            self.ptr.pin();
             Pin::new_unchecked(self)
         }
     }
@@ -0,0 +1,46 @@
+use std::cell::Ref;
+use std::pin::Pin;
+
+use gc::Trace;
+use pin_cell::{PinCell, PinMut};
+
+use crate::Gc;
+
+/// The rooted form of a `PinCell<GcStore<'root, T>>` field.
+///
+/// Writes go through the collector's write barrier, so that a collection in
+/// progress still sees every object which is moved into a traced field.
+#[repr(transparent)]
+pub struct GcPinCell<'root, T: ?Sized + 'root> {
+    cell: PinCell<Gc<'root, T>>,
+}
+
+impl<'root, T: ?Sized> GcPinCell<'root, T> {
+    pub fn borrow(&self) -> Ref<'_, Gc<'root, T>> {
+        let value = self.cell.borrow();
+        unsafe { Gc::raw(*value).hold(); }
+        value
+    }
+
+    pub fn get(&self) -> Gc<'root, T> {
+        *self.borrow()
+    }
+}
+
+impl<'root, T: Trace + ?Sized> GcPinCell<'root, T> {
+    pub fn set(&self, value: Gc<'_, T>) {
+        unsafe {
+            let old = *self.cell.borrow();
+            if !Gc::raw(old).same_heap(Gc::raw(value)) {
+                panic!("an object managed by one heap cannot be used in another");
+            }
+
+            // The cell is a field of a managed object, which never moves. It is
+            // only borrowed once no other thread can be tracing it.
+            gc::write(Gc::raw(old), Gc::raw(value), || {
+                let mut cell = Pin::new_unchecked(&self.cell).borrow_mut();
+                PinMut::as_mut(&mut cell).set(Gc::rooted(Gc::raw(value)));
+            })
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::ptr;
+
+use gc::Trace;
+
+use crate::{Gc, Root};
+use crate::root::Reroot;
+
+/// A reference to a GC'd object which keeps it alive until the heap comes under
+/// memory pressure, or the reference goes unused for too long
+///
+/// When to give up soft references is configured with `GcConfig`. Once given
+/// up, a GcSoft is cleared like a `GcWeak` by the collection which finds its
+/// object dead. Only major collections count towards how long a GcSoft has
+/// gone unused.
+pub struct GcSoft<T: ?Sized> {
+    soft: gc::Soft<T>,
+}
+
+impl<T: ?Sized> GcSoft<T> {
+    pub fn new(gc: Gc<'_, T>) -> GcSoft<T> {
+        unsafe {
+            GcSoft { soft: gc::Soft::new(Gc::raw(gc)) }
+        }
+    }
+
+    /// Root the object this refers to, unless it has been collected, counting
+    /// the reference as used
+    pub fn upgrade<'root>(&self, root: Root<'root>) -> Option<Gc<'root, T::Rerooted>> where
+        T: Reroot<'root>,
+        T::Rerooted: Trace,
+    {
+        root.make_from(|| self.soft.get())
+    }
+
+    /// Tell if the object this refers to has been collected
+    pub fn is_cleared(&self) -> bool {
+        self.soft.peek().is_none()
+    }
+}
+
+impl<T: ?Sized> Clone for GcSoft<T> {
+    fn clone(&self) -> GcSoft<T> {
+        GcSoft { soft: self.soft.clone() }
+    }
+}
+
+impl<'a, T: ?Sized> From<Gc<'a, T>> for GcSoft<T> {
+    fn from(gc: Gc<'a, T>) -> GcSoft<T> {
+        GcSoft::new(gc)
+    }
+}
+
+unsafe impl<'root, T: Reroot<'root> + ?Sized> Reroot<'root> for GcSoft<T> {
+    type Rerooted = GcSoft<T::Rerooted>;
+}
+
+// The heap keeps the object alive, and updates the reference when it moves.
+unsafe impl<T: ?Sized> Trace for GcSoft<T> {
+    unsafe fn mark(&self) { }
+    unsafe fn manage(&self) { }
+    unsafe fn finalize(&mut self) {
+        ptr::drop_in_place(self as *mut Self)
+    }
+    unsafe fn relocate(&mut self) { }
+    fn is_leaf() -> bool { true }
+}
@@ -9,6 +9,11 @@ pub struct GcStore<'root, T: ?Sized + 'root> {
     _marker: PhantomData<(&'root T, PhantomPinned)>,
 }
 
+// A GcStore may point to managed data which is also reachable through a Gc on
+// another thread, so it is sent on the same terms as a Gc as well.
+unsafe impl<'root, T: Send + Sync + ?Sized> Send for GcStore<'root, T> { }
+unsafe impl<'root, T: Sync + ?Sized> Sync for GcStore<'root, T> { }
+
 impl<'root, T: Trace> GcStore<'root, T> {
     pub fn new(data: T) -> GcStore<'root, T> {
         GcStore {
@@ -62,6 +67,10 @@ unsafe impl<'root, T: Trace + ?Sized> Trace for GcStore<'root, T> {
     }
 
     unsafe fn finalize(&mut self) { }
+
+    unsafe fn relocate(&mut self) {
+        self.ptr.relocate();
+    }
 }
 
 impl<'root, T: ?Sized + Trace> From<Gc<'root, T>> for GcStore<'root, T> {
@@ -0,0 +1,63 @@
+use std::ptr;
+
+use gc::Trace;
+
+use crate::{Gc, Root};
+use crate::root::Reroot;
+
+/// A reference to a GC'd object which does not keep it alive
+///
+/// A GcWeak can be stored in GC'd objects or in unmanaged data. It is cleared
+/// by the collection which finds its object dead, before that object is
+/// finalized.
+pub struct GcWeak<T: ?Sized> {
+    weak: gc::Weak<T>,
+}
+
+impl<T: ?Sized> GcWeak<T> {
+    pub fn new(gc: Gc<'_, T>) -> GcWeak<T> {
+        unsafe {
+            GcWeak { weak: gc::Weak::new(Gc::raw(gc)) }
+        }
+    }
+
+    /// Root the object this refers to, unless it has already been collected
+    pub fn upgrade<'root>(&self, root: Root<'root>) -> Option<Gc<'root, T::Rerooted>> where
+        T: Reroot<'root>,
+        T::Rerooted: Trace,
+    {
+        root.make_from(|| self.weak.get())
+    }
+
+    /// Tell if the object this refers to has been collected
+    pub fn is_cleared(&self) -> bool {
+        self.weak.get().is_none()
+    }
+}
+
+impl<T: ?Sized> Clone for GcWeak<T> {
+    fn clone(&self) -> GcWeak<T> {
+        GcWeak { weak: self.weak.clone() }
+    }
+}
+
+impl<'a, T: ?Sized> From<Gc<'a, T>> for GcWeak<T> {
+    fn from(gc: Gc<'a, T>) -> GcWeak<T> {
+        GcWeak::new(gc)
+    }
+}
+
+unsafe impl<'root, T: Reroot<'root> + ?Sized> Reroot<'root> for GcWeak<T> {
+    type Rerooted = GcWeak<T::Rerooted>;
+}
+
+// A weak reference is never traced; the heap updates it when its object moves.
+unsafe impl<T: ?Sized> Trace for GcWeak<T> {
+    unsafe fn mark(&self) { }
+    unsafe fn manage(&self) { }
+    unsafe fn finalize(&mut self) {
+        ptr::drop_in_place(self as *mut Self)
+    }
+    unsafe fn relocate(&mut self) { }
+    fn is_leaf() -> bool { true }
+}
@@ -0,0 +1,132 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ptr;
+
+use gc::{Ephemeron, NullTrace, Trace};
+
+use crate::Gc;
+use crate::root::Reroot;
+
+/// A map from GC'd objects to values which are kept alive only as long as
+/// their keys are reachable from elsewhere
+///
+/// Each value is managed by the heap which manages its key, and is collected
+/// along with the key. A value may point back to its own key without keeping
+/// it alive.
+pub struct GcWeakKeyMap<K: ?Sized, V> {
+    // Compacting the heap moves the keys, and updates the addresses the heap
+    // keeps in their ephemerons, so the index from the addresses of the keys is
+    // rebuilt from the ephemerons once any heap has been compacted. An entry
+    // whose key has been collected is cleared by the heap, and is purged once
+    // enough of them have built up.
+    entries: Vec<Option<Ephemeron<K, V>>>,
+    index: RefCell<Index>,
+    purge_at: usize,
+}
+
+struct Index {
+    entries: HashMap<usize, usize>,
+    compactions: usize,
+}
+
+impl<K: ?Sized, V: Trace> GcWeakKeyMap<K, V> {
+    pub fn new() -> GcWeakKeyMap<K, V> {
+        let index = Index { entries: HashMap::new(), compactions: gc::compactions() };
+        GcWeakKeyMap { entries: vec![], index: RefCell::new(index), purge_at: 8 }
+    }
+
+    pub fn insert(&mut self, key: Gc<'_, K>, value: V) {
+        if self.entries.len() >= self.purge_at {
+            self.entries.retain(|entry| entry.as_ref().map_or(false, |entry| entry.get().is_some()));
+            self.purge_at = self.entries.len() * 2 + 8;
+            self.reindex();
+        }
+        let entry = unsafe { Ephemeron::new(Gc::raw(key), value) };
+        match self.find(&*key) {
+            Some(idx)   => self.entries[idx] = Some(entry),
+            None        => {
+                self.entries.push(Some(entry));
+                self.index.get_mut().entries.insert(address(&*key), self.entries.len() - 1);
+            }
+        }
+    }
+
+    /// The value for a key, which lives at least as long as the key is rooted
+    pub fn get<'root>(&'root self, key: Gc<'root, K>) -> Option<Gc<'root, V>> {
+        let entry = self.entries[self.find(&*key)?].as_ref()?;
+        entry.get().map(|(_, value)| unsafe {
+            value.hold();
+            Gc::rooted(value)
+        })
+    }
+
+    pub fn contains_key(&self, key: Gc<'_, K>) -> bool {
+        self.find(&*key).and_then(|idx| self.entries[idx].as_ref()).map_or(false, |entry| entry.get().is_some())
+    }
+
+    /// Remove the entry for a key, returning true if there was one
+    pub fn remove(&mut self, key: Gc<'_, K>) -> bool {
+        let idx = match self.find(&*key) {
+            Some(idx)   => idx,
+            None        => return false,
+        };
+        self.index.get_mut().entries.remove(&address(&*key));
+        self.entries[idx].take().map_or(false, |entry| entry.get().is_some())
+    }
+
+    /// The number of entries whose keys have not been collected
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().filter(|entry| entry.get().is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: ?Sized, V> GcWeakKeyMap<K, V> {
+    // The entry for the key at this address, if it has one
+    fn find(&self, key: &K) -> Option<usize> {
+        if self.index.borrow().compactions != gc::compactions() {
+            self.reindex();
+        }
+        self.index.borrow().entries.get(&address(key)).cloned()
+    }
+
+    fn reindex(&self) {
+        let entries = self.entries.iter().enumerate().filter_map(|(idx, entry)| {
+            let (key, _) = entry.as_ref()?.get()?;
+            Some((address(unsafe { key.data() }), idx))
+        }).collect();
+        *self.index.borrow_mut() = Index { entries, compactions: gc::compactions() };
+    }
+}
+
+impl<K: ?Sized, V: Trace> Default for GcWeakKeyMap<K, V> {
+    fn default() -> GcWeakKeyMap<K, V> {
+        GcWeakKeyMap::new()
+    }
+}
+
+fn address<K: ?Sized>(key: &K) -> usize {
+    key as *const K as *const u8 as usize
+}
+
+unsafe impl<'root, K: Reroot<'root> + ?Sized, V: Reroot<'root>> Reroot<'root> for GcWeakKeyMap<K, V> where
+    V::Rerooted: Sized,
+{
+    type Rerooted = GcWeakKeyMap<K::Rerooted, V::Rerooted>;
+}
+
+// The values are traced by the heap from their keys, never from the map.
+unsafe impl<K: ?Sized, V> Trace for GcWeakKeyMap<K, V> {
+    unsafe fn mark(&self) { }
+    unsafe fn manage(&self) { }
+    unsafe fn finalize(&mut self) {
+        ptr::drop_in_place(self as *mut Self)
+    }
+    unsafe fn relocate(&mut self) { }
+    fn is_leaf() -> bool { true }
+}
+
+unsafe impl<K: ?Sized, V> NullTrace for GcWeakKeyMap<K, V> { }
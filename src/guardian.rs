@@ -0,0 +1,42 @@
+use gc::Trace;
+
+use crate::{Gc, Root};
+use crate::root::Reroot;
+
+/// Keeps the objects registered with it alive once they are otherwise
+/// unreachable, and hands them back instead of letting them be collected
+///
+/// An object is handed back once for every time it was registered. Objects
+/// reachable only from other guarded objects are handed back by the same
+/// collection, and weak references to handed back objects are not cleared.
+/// Dropping the guardian leaves its objects to be collected as usual.
+pub struct Guardian<T> {
+    guardian: gc::Guardian<T>,
+}
+
+impl<T> Guardian<T> {
+    /// Create a guardian in the current heap
+    pub fn new() -> Guardian<T> {
+        Guardian { guardian: gc::Guardian::new() }
+    }
+
+    pub fn register(&self, gc: Gc<'_, T>) {
+        unsafe {
+            self.guardian.register(Gc::raw(gc))
+        }
+    }
+
+    /// Root the next object which has been found unreachable, if any
+    pub fn poll<'root>(&self, root: Root<'root>) -> Option<Gc<'root, T::Rerooted>> where
+        T: Reroot<'root>,
+        T::Rerooted: Trace,
+    {
+        root.make_from(|| self.guardian.poll())
+    }
+}
+
+impl<T> Default for Guardian<T> {
+    fn default() -> Guardian<T> {
+        Guardian::new()
+    }
+}
@@ -3,8 +3,14 @@
 // the bridge, the only bridge, across what divided us.
 //      - Ursula K. Le Guin
 
+mod finalization_registry;
 mod gc;
+mod gc_pin_cell;
+mod gc_soft;
 mod gc_store;
+mod gc_weak;
+mod gc_weak_key_map;
+mod guardian;
 mod no_trace;
 mod root;
 mod store;
@@ -12,21 +18,30 @@ mod store;
 #[cfg(test)]
 mod tests;
 
-pub use ::gc::{collect};
+pub use ::gc::{collect, collect_minor, collect_step, sweep_step, run_finalizers};
+pub use ::gc::{GcConfig, Enter, Heap, Mutator, SyncHeap};
 pub use derive::*;
 
 pub mod raw {
     pub use gc::{GcPtr, alloc, alloc_unmanaged, manage, Root};
-    pub use gc::{count_managed_objects, count_roots};
+    pub use gc::{count_managed_objects, count_young_objects, count_roots, count_pending_finalizers};
+    pub use gc::{config, heap_size, large_object_space_size, safepoint};
     pub use gc::{Trace, NullTrace};
+    pub use gc::{compact, on_collect, write, write_barrier};
     pub use crate::store::*;
     pub use crate::root::Reroot;
 }
 
+pub use self::finalization_registry::*;
 pub use self::gc::*;
+pub use self::gc_pin_cell::*;
+pub use self::gc_soft::*;
 pub use self::gc_store::*;
+pub use self::gc_weak::*;
+pub use self::gc_weak_key_map::*;
+pub use self::guardian::*;
 pub use self::no_trace::*;
-pub use self::root::Root;
+pub use self::root::{HeapRoot, Root};
 
 pub trait Finalize {
     fn finalize(&mut self);
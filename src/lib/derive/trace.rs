@@ -8,11 +8,18 @@ pub fn trace_impl(s: &Structure) -> TokenStream {
     let mark_body = s.each(|b| quote!(#b.mark()));
     let manage_body = s.each(|b| quote!(#b.manage()));
     let finalize_body = s.clone().bind_with(|_| BindStyle::RefMut).each(|b| quote!(#b.finalize()));
+    let relocate_body = s.clone().bind_with(|_| BindStyle::RefMut).each(|b| quote!(#b.relocate()));
     let drop = has_drop(s);
     let drop_glue = match &drop {
         HasDrop::None       => quote!(),
         _                   => quote!(shifgrethor::Finalize::finalize(self)),
     };
+    // A type with a finalizer must be finalized, even if it has no fields to trace
+    let fields = s.variants().iter().flat_map(|v| v.bindings()).map(|b| &b.ast().ty);
+    let is_leaf = match &drop {
+        HasDrop::None       => quote!(true #(&& <#fields as shifgrethor::raw::Trace>::is_leaf())*),
+        _                   => quote!(false),
+    };
     let bound = match &drop {
         HasDrop::Drop       => {
             assert!(only_has_root_lifetime(s), "GC'd objects with lifetimes other than 'root must use UnsafeFinalize");
@@ -36,6 +43,12 @@ pub fn trace_impl(s: &Structure) -> TokenStream {
                 match self { #finalize_body }
                 #drop_glue
             }
+            unsafe fn relocate(&mut self) {
+                match self { #relocate_body }
+            }
+            fn is_leaf() -> bool {
+                #is_leaf
+            }
         }
     })
 }
@@ -1,10 +1,9 @@
-use std::cell::Cell;
+use std::alloc::Layout;
 use std::mem;
-use std::ptr::NonNull;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
 
-use log::*;
-
-use crate::list::List;
+use crate::page;
 use crate::trace::Trace;
 
 extern {
@@ -12,60 +11,235 @@ extern {
     type Vtable;
 }
 
+#[repr(C)]
 pub struct Allocation<T: ?Sized> {
     header: Header,
     pub(crate) data: T,
 }
 
+// Two words: the heap keeps track of its objects in tables of its own, and
+// their mark bits are kept in side bitmaps. Each object still costs a third
+// word, for its entry in those tables.
 struct Header {
-    list: List<Allocation<Data>>,
+    // Once the object has been relocated, this holds the forwarding pointer
     vtable: *mut Vtable,
-    marked: Cell<bool>,
+    // The id of the heap which manages this object, or zero while unmanaged.
+    // These are atomic because an object may be pinned from any thread it is
+    // shared with while its heap is collected.
+    heap: AtomicU32,
+    flags: AtomicU8,
 }
 
+const OLD: u8 = 1 << 0;
+const PINNED: u8 = 1 << 1;
+const FORWARDED: u8 = 1 << 2;
+// The memory of an object comes from a page or a nursery unless it has one of
+// these flags, because it has no size class or because it is large. Large
+// objects are never moved.
+const SYSTEM: u8 = 1 << 3;
+const LARGE: u8 = 1 << 4;
+// Leaves are never traced, and those without drop glue are never finalized
+const LEAF: u8 = 1 << 5;
+const NO_DROP: u8 = 1 << 6;
+// Held objects are pinned only until the end of the next collection
+const HELD: u8 = 1 << 7;
+
 impl<T: Trace> Allocation<T> {
     pub fn new(data: T) -> NonNull<Allocation<T>> {
         let vtable = extract_vtable(&data);
+        let layout = Layout::new::<Allocation<T>>();
+        let space = if layout.size() > crate::large_object_size() {
+            LARGE
+        } else {
+            space(layout)
+        };
+        let leaf = match (T::is_leaf(), mem::needs_drop::<T>()) {
+            (true, true)    => LEAF,
+            (true, false)   => LEAF | NO_DROP,
+            (false, _)      => 0,
+        };
 
-        let allocation = Box::new(Allocation {
+        let allocation = Allocation {
             header: Header {
-                list: List::default(),
                 vtable: vtable,
-                marked: Cell::new(false),
+                heap: AtomicU32::new(0),
+                flags: AtomicU8::new(space | leaf),
             },
             data,
-        });
+        };
         unsafe {
-            NonNull::new_unchecked(Box::into_raw(allocation))
+            let ptr = match space {
+                0   => page::alloc_young(layout),
+                _   => page::alloc_large(layout),
+            }.cast::<Allocation<T>>();
+            ptr::write(ptr.as_ptr(), allocation);
+            ptr
         }
     }
 }
 
 impl Allocation<Data> {
     pub unsafe fn free(self: *mut Allocation<Data>) {
-        (&mut *self).dyn_data_mut().finalize();
-        drop(Box::from_raw(self))
+        let (layout, _) = (&*self).layout();
+        if !(*self).header.flag(NO_DROP) {
+            (&mut *self).dyn_data_mut().finalize();
+        }
+        self.release(layout);
+    }
+
+    /// Move this object into a new allocation, leaving a forwarding pointer
+    /// behind. Returns the new allocation and the layout of the old one.
+    pub unsafe fn relocate(self: *mut Allocation<Data>) -> (NonNull<Allocation<Data>>, Layout) {
+        let (layout, offset) = (&*self).layout();
+        let size = mem::size_of_val((&*self).dyn_data());
+        let new = page::alloc(layout).as_ptr() as *mut Allocation<Data>;
+
+        ptr::write(new as *mut Header, Header {
+            vtable: (*self).header.vtable,
+            heap: AtomicU32::new((*self).heap()),
+            flags: AtomicU8::new((*self).header.flags.load(Ordering::Relaxed) & (OLD | LEAF | NO_DROP) | space(layout)),
+        });
+        ptr::copy_nonoverlapping((self as *const u8).add(offset), (new as *mut u8).add(offset), size);
+
+        (*self).header.vtable = new as *mut Vtable;
+        (*self).header.set_flag(FORWARDED);
+        (NonNull::new_unchecked(new), layout)
+    }
+
+    /// Free the memory of this object without finalizing its data
+    pub unsafe fn release(self: *mut Allocation<Data>, layout: Layout) {
+        dealloc(self as *mut u8, layout, (*self).is_paged());
+    }
+
+    /// The number of bytes in this allocation
+    pub fn size(&self) -> usize {
+        self.layout().0.size()
+    }
+
+    pub fn forwarded(&self) -> Option<NonNull<Allocation<Data>>> {
+        if self.header.flag(FORWARDED) {
+            NonNull::new(self.header.vtable as *mut Allocation<Data>)
+        } else {
+            None
+        }
+    }
+
+    /// Update every pointer in this object to a relocated object
+    pub unsafe fn relocate_pointers(self: *mut Allocation<Data>) {
+        if !(*self).header.flag(LEAF) {
+            (&mut *self).dyn_data_mut().relocate()
+        }
+    }
+
+    // The layout of the whole allocation and the offset of its data
+    fn layout(&self) -> (Layout, usize) {
+        let data = self.dyn_data();
+        let data = Layout::from_size_align(mem::size_of_val(data), mem::align_of_val(data)).unwrap();
+        let (layout, offset) = Layout::new::<Header>().extend(data).unwrap();
+        (layout.pad_to_align(), offset)
     }
 }
 
 impl<T: ?Sized> Allocation<T> {
-    pub unsafe fn mark(&self) {
-        debug!("MARKING object at:          {:x}", self.erased() as *const _ as usize);
-        if !self.header.marked.replace(true) {
-            self.dyn_data().mark()
-        }
+    /// Drop and free an unmanaged allocation
+    pub unsafe fn deallocate(self: *mut Allocation<T>) {
+        let layout = Layout::for_value(&*self);
+        let paged = (*self).is_paged();
+        ptr::drop_in_place(self);
+        dealloc(self as *mut u8, layout, paged);
+    }
+
+    /// Mark this object, returning true if it was previously unmarked
+    ///
+    /// An object is marked when its mark bit is equal to its heap's color.
+    pub fn mark(&self, color: bool) -> bool {
+        let (word, mask) = self.mark_bit();
+        let old = match color {
+            true    => word.fetch_or(mask, Ordering::Relaxed),
+            false   => word.fetch_and(!mask, Ordering::Relaxed),
+        };
+        (old & mask != 0) != color
+    }
+
+    pub fn unmark(&self, color: bool) {
+        self.mark(!color);
+    }
+
+    pub fn is_marked(&self, color: bool) -> bool {
+        let (word, mask) = self.mark_bit();
+        (word.load(Ordering::Relaxed) & mask != 0) == color
+    }
+
+    /// Mark everything this object points to
+    pub unsafe fn trace(&self) {
+        self.dyn_data().mark()
+    }
+
+    /// Manage everything this object points to
+    pub unsafe fn manage_children(&self) {
+        self.dyn_data().manage()
     }
 
     pub fn data(&self) -> &T {
         &self.data
     }
 
-    pub fn marked(&self) -> bool {
-        self.header.marked.replace(false)
+    pub fn is_unmanaged(&self) -> bool {
+        self.heap() == 0
+    }
+
+    pub fn heap(&self) -> u32 {
+        self.header.heap.load(Ordering::Relaxed)
     }
 
-    pub fn is_unmanaged(&self) -> bool {
-        self.header.list.is_head()
+    pub fn set_heap(&self, heap: u32) {
+        self.header.heap.store(heap, Ordering::Relaxed);
+    }
+
+    pub fn is_old(&self) -> bool {
+        self.header.flag(OLD)
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.header.flag(LEAF)
+    }
+
+    /// Tell if this object must be finalized before it is freed
+    pub fn needs_finalize(&self) -> bool {
+        !self.header.flag(NO_DROP)
+    }
+
+    pub fn is_large(&self) -> bool {
+        self.header.flag(LARGE)
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.header.flag(PINNED)
+    }
+
+    pub fn pin(&self) {
+        self.header.set_flag(PINNED);
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.header.flag(HELD)
+    }
+
+    /// Hold the object in place, returning false if it was already held
+    pub fn hold(&self) -> bool {
+        self.header.flags.fetch_or(HELD, Ordering::Relaxed) & HELD == 0
+    }
+
+    pub fn let_go(&self) {
+        self.header.flags.fetch_and(!HELD, Ordering::Relaxed);
+    }
+
+    pub fn promote(&self) {
+        self.header.set_flag(OLD);
+    }
+
+    fn is_paged(&self) -> bool {
+        !self.header.flag(SYSTEM | LARGE)
     }
 
     fn dyn_data(&self) -> &dyn Trace {
@@ -88,6 +262,10 @@ impl<T: ?Sized> Allocation<T> {
         }
     }
 
+    fn mark_bit(&self) -> (&AtomicU64, u64) {
+        unsafe { page::mark_bit(self as *const Allocation<T> as *const u8, self.is_paged()) }
+    }
+
     fn erased(&self) -> &Allocation<Data> {
         unsafe {
             &*(self as *const Allocation<T> as *const Allocation<Data>)
@@ -95,9 +273,26 @@ impl<T: ?Sized> Allocation<T> {
     }
 }
 
-impl AsRef<List<Allocation<Data>>> for Allocation<Data> {
-    fn as_ref(&self) -> &List<Allocation<Data>> {
-        &self.header.list
+impl Header {
+    // Tell if any of these flags is set
+    fn flag(&self, flags: u8) -> bool {
+        self.flags.load(Ordering::Relaxed) & flags != 0
+    }
+
+    fn set_flag(&self, flag: u8) {
+        self.flags.fetch_or(flag, Ordering::Relaxed);
+    }
+}
+
+// The flag for where the memory of an object with this layout comes from
+fn space(layout: Layout) -> u8 {
+    if page::is_paged(layout) { 0 } else { SYSTEM }
+}
+
+unsafe fn dealloc(ptr: *mut u8, layout: Layout, paged: bool) {
+    match paged {
+        true    => page::dealloc(ptr, layout),
+        false   => page::dealloc_large(ptr, layout),
     }
 }
 
@@ -0,0 +1,183 @@
+use log::LevelFilter;
+
+/// Settings for the current heap's collector.
+///
+/// A configuration can be installed at any time. Thresholds are rescheduled
+/// as soon as it is installed, but the heap is not collected until the next
+/// safepoint.
+#[derive(Copy, Clone, Debug)]
+pub struct GcConfig {
+    pub(crate) initial_threshold: usize,
+    pub(crate) growth: usize,
+    pub(crate) max_heap_size: Option<usize>,
+    pub(crate) stress: bool,
+    pub(crate) mark_threads: usize,
+    pub(crate) concurrent: bool,
+    pub(crate) lazy_sweep: usize,
+    pub(crate) nursery_size: usize,
+    pub(crate) large_object_size: usize,
+    pub(crate) defer_finalizers: bool,
+    pub(crate) soft_pressure: Option<usize>,
+    pub(crate) soft_max_idle: usize,
+    pub(crate) soft_policy: Option<fn(usize, usize) -> bool>,
+    pub(crate) log_level: LevelFilter,
+}
+
+impl Default for GcConfig {
+    fn default() -> GcConfig {
+        GcConfig {
+            initial_threshold: 1 << 20,
+            growth: 100,
+            max_heap_size: None,
+            stress: false,
+            mark_threads: 1,
+            concurrent: false,
+            lazy_sweep: 0,
+            nursery_size: 1 << 18,
+            large_object_size: 1 << 16,
+            defer_finalizers: false,
+            soft_pressure: None,
+            soft_max_idle: 8,
+            soft_policy: None,
+            log_level: LevelFilter::Trace,
+        }
+    }
+}
+
+impl GcConfig {
+    pub fn new() -> GcConfig {
+        GcConfig::default()
+    }
+
+    /// The heap size, in bytes, below which allocation never triggers a
+    /// collection.
+    pub fn initial_threshold(mut self, bytes: usize) -> GcConfig {
+        self.initial_threshold = bytes;
+        self
+    }
+
+    /// How much the heap may grow, as a percentage of the bytes which survived
+    /// the last collection, before allocation triggers another collection.
+    pub fn growth(mut self, percent: usize) -> GcConfig {
+        self.growth = percent;
+        self
+    }
+
+    /// The heap size, in bytes, which the live objects may never exceed. If a
+    /// safepoint finds the heap larger than this, it finishes a full collection
+    /// and finalizes every dead object, then panics if the heap is still larger.
+    pub fn max_heap_size(mut self, bytes: usize) -> GcConfig {
+        self.max_heap_size = Some(bytes);
+        self
+    }
+
+    /// Collect at every safepoint, which is useful for finding rooting bugs.
+    pub fn stress(mut self, stress: bool) -> GcConfig {
+        self.stress = stress;
+        self
+    }
+
+    /// The number of threads which trace the heap when it is marked all at
+    /// once. The heap keeps the extra threads, parked between collections.
+    /// Incremental steps are always marked on the collecting thread.
+    pub fn mark_threads(mut self, threads: usize) -> GcConfig {
+        self.mark_threads = threads.max(1);
+        self
+    }
+
+    /// Mark on a background thread while the mutator keeps running, finishing
+    /// with a short pause at a later safepoint. Every write to a traced location
+    /// must go through `write`, as `GcPinCell::set` does.
+    pub fn concurrent(mut self, concurrent: bool) -> GcConfig {
+        self.concurrent = concurrent;
+        self
+    }
+
+    /// Sweep lazily, examining this many objects left by the last collection
+    /// at each safepoint. Zero, the default, sweeps the whole heap as soon as
+    /// it has been marked.
+    pub fn lazy_sweep(mut self, objects: usize) -> GcConfig {
+        self.lazy_sweep = objects;
+        self
+    }
+
+    /// How many bytes of young objects a heap may gain, on any of its threads,
+    /// before the next safepoint collects its young generation. Zero never
+    /// collects because the nursery is full.
+    ///
+    /// Survivors are promoted where they are rather than evacuated. Once a
+    /// nursery page is full, the holes left between them by the objects freed
+    /// since are bumped through again, by its thread or by another once its
+    /// thread has moved on to a fresh page.
+    pub fn nursery_size(mut self, bytes: usize) -> GcConfig {
+        self.nursery_size = bytes;
+        self
+    }
+
+    /// Objects larger than this many bytes are allocated on their own, straight
+    /// from the system allocator. They are never moved, and once a collection
+    /// has seen them only major collections free them. This applies to the
+    /// objects allocated afterwards while the heap is current.
+    ///
+    /// A large object is freed as soon as a collection finds it dead, unless it
+    /// has drop glue: its memory is then kept until it has been finalized, which
+    /// with `defer_finalizers` is only once `run_finalizers` is called.
+    pub fn large_object_size(mut self, bytes: usize) -> GcConfig {
+        self.large_object_size = bytes;
+        self
+    }
+
+    /// Only finalize dead objects when `run_finalizers` is called, instead of
+    /// as soon as the sweep which found them dead is finished. They are also
+    /// finalized before the heap is found to exceed `max_heap_size`. The memory
+    /// of each object is kept until it has been finalized, even a large one.
+    pub fn defer_finalizers(mut self, defer: bool) -> GcConfig {
+        self.defer_finalizers = defer;
+        self
+    }
+
+    /// Clear soft references to objects which are otherwise unreachable once the
+    /// heap is larger than this many bytes when a major collection begins.
+    pub fn soft_pressure(mut self, bytes: usize) -> GcConfig {
+        self.soft_pressure = Some(bytes);
+        self
+    }
+
+    /// Clear soft references to objects which are otherwise unreachable once
+    /// they have gone unused for this many major collections. The default is 8.
+    pub fn soft_max_idle(mut self, collections: usize) -> GcConfig {
+        self.soft_max_idle = collections;
+        self
+    }
+
+    /// Decide which soft references to keep with a function of the heap size in
+    /// bytes and the number of major collections for which a reference has gone
+    /// unused, in place of `soft_pressure` and `soft_max_idle`.
+    pub fn soft_policy(mut self, keep: fn(usize, usize) -> bool) -> GcConfig {
+        self.soft_policy = Some(keep);
+        self
+    }
+
+    /// The most verbose messages the collector will log. Collections are logged
+    /// at `Debug`, and individual objects and roots at `Trace`.
+    pub fn log_level(mut self, level: LevelFilter) -> GcConfig {
+        self.log_level = level;
+        self
+    }
+
+    /// Use this configuration for the current heap's collector.
+    pub fn install(self) {
+        super::configure(self)
+    }
+
+    // Whether a soft reference should keep its object alive for another major
+    // collection
+    pub(crate) fn keep_soft(&self, heap_bytes: usize, idle: usize) -> bool {
+        match self.soft_policy {
+            Some(keep)  => keep(heap_bytes, idle),
+            None        => {
+                idle < self.soft_max_idle && self.soft_pressure.map_or(true, |bytes| heap_bytes <= bytes)
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::ptr::NonNull;
+
+use crate::gc_ptr::GcPtr;
+use crate::heap;
+use crate::trace::Trace;
+
+/// A pair of managed objects, of which the value is kept alive only as long as
+/// the key is reachable from elsewhere
+///
+/// The ephemeron is cleared by the collection which finds its key dead, before
+/// either object is finalized.
+pub struct Ephemeron<K: ?Sized, V: ?Sized> {
+    idx: usize,
+    heap: u32,
+    // Only the addresses of the objects are kept by the heap
+    key: GcPtr<K>,
+    value: GcPtr<V>,
+}
+
+impl<K: ?Sized, V: Trace> Ephemeron<K, V> {
+    /// Manage the value in the heap which manages the key
+    ///
+    /// Invariants: key must not be dangling and must be managed
+    pub unsafe fn new(key: GcPtr<K>, value: V) -> Ephemeron<K, V> {
+        let heap = key.heap();
+        let value = GcPtr::new(value);
+        let idx = heap::with_heap(heap, |gc| {
+            // The value's children are managed by the current heap
+            crate::enter(Some(NonNull::from(&*gc)), || gc.manage(value));
+            gc.new_ephemeron(key.erased(), value.erased())
+        });
+        Ephemeron { idx, heap, key, value }
+    }
+}
+
+impl<K: ?Sized, V: ?Sized> Ephemeron<K, V> {
+    /// The key and value, unless the key has been collected
+    ///
+    /// The objects must be rooted before the heap is next collected.
+    pub fn get(&self) -> Option<(GcPtr<K>, GcPtr<V>)> {
+        let objects = heap::try_with_heap(self.heap, |gc| gc.ephemeron(self.idx));
+        objects.and_then(|objects| objects).map(|(key, value)| {
+            (self.key.with_address(key), self.value.with_address(value))
+        })
+    }
+}
+
+impl<K: ?Sized, V: ?Sized> Drop for Ephemeron<K, V> {
+    fn drop(&mut self) {
+        // The heap may already have been dropped, along with its ephemerons
+        heap::try_with_heap(self.heap, |gc| gc.drop_ephemeron(self.idx));
+    }
+}
@@ -2,6 +2,7 @@ use std::pin::Pin;
 use std::ptr::NonNull;
 
 use crate::alloc::{Allocation, Data};
+use crate::heap;
 use crate::trace::Trace;
 
 pub struct GcPtr<T: ?Sized> {
@@ -16,6 +17,13 @@ impl<T: Trace> GcPtr<T> {
     }
 }
 
+impl<T> GcPtr<T> {
+    // Invariants: the object must hold a T
+    pub(crate) unsafe fn from_erased(ptr: NonNull<Allocation<Data>>) -> GcPtr<T> {
+        GcPtr { inner: ptr.cast() }
+    }
+}
+
 impl<T: ?Sized> GcPtr<T> {
     /// Get a reference to the GC'd data
     ///
@@ -31,11 +39,41 @@ impl<T: ?Sized> GcPtr<T> {
         self.inner.as_ref().is_unmanaged()
     }
 
+    /// Tell if this ptr is managed by the same heap as another
+    ///
+    /// Invariants: neither GcPtr may be dangling
+    pub unsafe fn same_heap<U: ?Sized>(&self, other: GcPtr<U>) -> bool {
+        self.heap() == other.heap()
+    }
+
+    pub(crate) unsafe fn heap(&self) -> u32 {
+        self.inner.as_ref().heap()
+    }
+
+    /// Prevent the data behind this GcPtr from ever being relocated
+    ///
+    /// Invariants: GcPtr must not be dangling
+    pub unsafe fn pin(&self) {
+        self.inner.as_ref().pin()
+    }
+
+    /// Prevent the data behind this GcPtr from being relocated until the end of
+    /// the next collection of its heap
+    ///
+    /// Invariants: GcPtr must not be dangling
+    pub unsafe fn hold(&self) {
+        let object = self.inner.as_ref();
+        // Unmanaged objects are never relocated
+        if !object.is_unmanaged() && object.hold() {
+            heap::with_heap(object.heap(), |gc| gc.hold(self.erased()))
+        }
+    }
+
     /// Free the data behind this GcPtr
     ///
     /// Invariants: GcPtr must not be dangling, must not be managed and must not be read again
     pub unsafe fn deallocate(self) {
-        drop(Box::from_raw(self.inner.as_ptr()))
+        self.inner.as_ptr().deallocate()
     }
 
     pub(crate) fn erased(self) -> NonNull<Allocation<Data>> {
@@ -44,6 +82,15 @@ impl<T: ?Sized> GcPtr<T> {
         }
     }
 
+    // The same pointer at a new address, keeping any pointer metadata
+    pub(crate) fn with_address(self, address: NonNull<Allocation<Data>>) -> GcPtr<T> {
+        let mut ptr = self.inner.as_ptr();
+        unsafe {
+            *(&mut ptr as *mut *mut Allocation<T> as *mut *mut Allocation<Data>) = address.as_ptr();
+            GcPtr { inner: NonNull::new_unchecked(ptr) }
+        }
+    }
+
     pub(crate) unsafe fn erased_pinned<'a>(self) -> Pin<&'a Allocation<Data>> {
         Pin::new_unchecked(&*self.erased().as_ptr())
     }
@@ -51,7 +98,7 @@ impl<T: ?Sized> GcPtr<T> {
 
 unsafe impl<T: Trace + ?Sized> Trace for GcPtr<T> {
     unsafe fn mark(&self) {
-        self.inner.as_ref().mark();
+        super::shade(*self)
     }
 
     unsafe fn manage(&self) {
@@ -59,6 +106,12 @@ unsafe impl<T: Trace + ?Sized> Trace for GcPtr<T> {
     }
 
     unsafe fn finalize(&mut self) { }
+
+    unsafe fn relocate(&mut self) {
+        if let Some(new) = self.erased().as_ref().forwarded() {
+            *self = self.with_address(new);
+        }
+    }
 }
 
 impl<T: ?Sized> Clone for GcPtr<T> {
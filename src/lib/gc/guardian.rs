@@ -0,0 +1,59 @@
+use std::marker::PhantomData;
+use std::pin::Pin;
+
+use crate::gc_ptr::GcPtr;
+use crate::heap::{self, Enter};
+use crate::state::GcState;
+
+/// Keeps the objects registered with it alive once they are found to be
+/// otherwise unreachable, and hands them back instead of freeing them
+pub struct Guardian<T> {
+    idx: usize,
+    heap: u32,
+    _marker: PhantomData<GcPtr<T>>,
+}
+
+impl<T> Guardian<T> {
+    /// Create a guardian in the current heap
+    pub fn new() -> Guardian<T> {
+        super::with_gc(Guardian::in_state)
+    }
+
+    /// Create a guardian in a particular heap
+    pub fn new_in<H: Enter>(heap: &H) -> Guardian<T> {
+        heap.enter(Guardian::new)
+    }
+
+    fn in_state(gc: Pin<&GcState>) -> Guardian<T> {
+        Guardian { idx: gc.new_guardian(), heap: gc.id(), _marker: PhantomData }
+    }
+
+    /// Invariants: ptr must not be dangling and must be managed
+    pub unsafe fn register(&self, ptr: GcPtr<T>) {
+        if ptr.heap() != self.heap {
+            panic!("an object managed by one heap cannot be used in another");
+        }
+        heap::with_heap(self.heap, |gc| gc.guard(self.idx, ptr.erased()))
+    }
+
+    /// The next object which has been found unreachable, if any
+    ///
+    /// The object must be rooted before the heap is next collected.
+    pub fn poll(&self) -> Option<GcPtr<T>> {
+        let object = heap::with_heap(self.heap, |gc| gc.poll_guardian(self.idx));
+        object.map(|object| unsafe { GcPtr::from_erased(object) })
+    }
+}
+
+impl<T> Default for Guardian<T> {
+    fn default() -> Guardian<T> {
+        Guardian::new()
+    }
+}
+
+impl<T> Drop for Guardian<T> {
+    fn drop(&mut self) {
+        // The heap may already have been dropped, along with its guardians
+        heap::try_with_heap(self.heap, |gc| gc.drop_guardian(self.idx));
+    }
+}
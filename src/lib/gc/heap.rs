@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::ManuallyDrop;
+use std::pin::Pin;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::state::GcState;
+
+// Heap ids are unique across threads, so that objects from a heap on another
+// thread are never mistaken for objects from a heap on this one.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    // Every heap which this thread can use, other than its default heap
+    static HEAPS: RefCell<HashMap<u32, NonNull<GcState>>> = RefCell::new(HashMap::new());
+}
+
+/// A heap which is collected independently of every other heap.
+///
+/// The free functions of this crate operate on the current heap, which is the
+/// thread's default heap unless another has been entered. Objects managed by
+/// one heap can never be stored in objects managed by another.
+///
+/// Dropping a heap frees every object in it. If a root into the heap is still
+/// alive, the heap is leaked instead.
+pub struct Heap {
+    state: ManuallyDrop<Pin<Box<GcState>>>,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        let state = Box::pin(GcState::new(next_id()));
+        register(state.as_ref());
+        Heap { state: ManuallyDrop::new(state) }
+    }
+
+    /// Run a function with this as the current heap
+    pub fn enter<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        super::enter(Some(NonNull::from(&*self.state())), f)
+    }
+
+    pub fn collect(&self) {
+        self.enter(super::collect)
+    }
+
+    pub(crate) fn state(&self) -> Pin<&GcState> {
+        self.state.as_ref()
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        if self.state().teardown() {
+            unregister(self.state());
+            unsafe { ManuallyDrop::drop(&mut self.state) }
+        }
+    }
+}
+
+/// Something which can be made the current heap
+pub trait Enter {
+    fn enter<T, F: FnOnce() -> T>(&self, f: F) -> T;
+}
+
+impl Enter for Heap {
+    fn enter<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        Heap::enter(self, f)
+    }
+}
+
+impl<'a, H: Enter> Enter for &'a H {
+    fn enter<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        (**self).enter(f)
+    }
+}
+
+pub(crate) fn next_id() -> u32 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed) as u32
+}
+
+/// Allow this thread to look up a heap by its id
+pub(crate) fn register(gc: Pin<&GcState>) {
+    HEAPS.with(|heaps| heaps.borrow_mut().insert(gc.id(), NonNull::from(&*gc)));
+}
+
+pub(crate) fn is_registered(id: u32) -> bool {
+    HEAPS.with(|heaps| heaps.borrow().contains_key(&id))
+}
+
+pub(crate) fn unregister(gc: Pin<&GcState>) {
+    HEAPS.with(|heaps| heaps.borrow_mut().remove(&gc.id()));
+}
+
+/// Look up a heap by its id
+pub(crate) fn with_heap<T, F: FnOnce(Pin<&GcState>) -> T>(id: u32, f: F) -> T {
+    try_with_heap(id, f).expect("heap has been dropped or belongs to another thread")
+}
+
+/// Look up a heap by its id, returning None if it has been dropped
+pub(crate) fn try_with_heap<T, F: FnOnce(Pin<&GcState>) -> T>(id: u32, f: F) -> Option<T> {
+    let gc = HEAPS.with(|heaps| heaps.borrow().get(&id).cloned());
+    match gc {
+        Some(gc)    => Some(super::with_state(gc, f)),
+        None        => super::with_default_gc(|gc| match gc.id() == id {
+            true    => Some(f(gc)),
+            false   => None,
+        }),
+    }
+}
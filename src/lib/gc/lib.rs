@@ -1,30 +1,72 @@
 #![feature(extern_types, arbitrary_self_types)]
+
+// Log through the `log` crate, unless filtered out by the GcConfig of the heap
+// or marker given first. These expect `log::*` to be imported where they are
+// used.
+macro_rules! gc_debug {
+    ($gc:expr, $($arg:tt)*) => {
+        if Level::Debug <= $gc.log_level() { debug!($($arg)*) }
+    }
+}
+
+macro_rules! gc_trace {
+    ($gc:expr, $($arg:tt)*) => {
+        if Level::Trace <= $gc.log_level() { trace!($($arg)*) }
+    }
+}
+
 mod alloc;
+mod config;
+mod ephemeron;
 mod gc_ptr;
-mod list;
+mod guardian;
+mod heap;
+mod mark;
+mod page;
+mod pacer;
 mod root;
 mod trace;
 mod state;
+mod soft;
+mod sync;
+mod weak;
 
+use std::cell::Cell;
 use std::pin::Pin;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::state::GcState;
 
+pub use crate::config::GcConfig;
+pub use crate::ephemeron::Ephemeron;
 pub use crate::gc_ptr::GcPtr;
+pub use crate::guardian::Guardian;
+pub use crate::heap::{Enter, Heap};
 pub use crate::root::Root;
+pub use crate::soft::Soft;
+pub use crate::sync::{Mutator, SyncHeap};
 pub use crate::trace::{Trace, NullTrace};
+pub use crate::weak::Weak;
 
 thread_local! {
-    static GC: GcState = GcState::default();
+    static GC: GcState = GcState::new(heap::next_id());
+    static CURRENT: Cell<Option<NonNull<GcState>>> = Cell::new(None);
 }
 
+// Counts the compactions of every heap
+static COMPACTIONS: AtomicUsize = AtomicUsize::new(0);
+
 /// Allocate an unmanaged GcPtr
 pub fn alloc_unmanaged<T: Trace>(data: T) -> GcPtr<T> {
     GcPtr::new(data)
 }
 
 /// Allocate a managed GcPtr
+///
+/// This is a safepoint, at which a collection may be performed.
 pub fn alloc<T: Trace>(data: T) -> GcPtr<T> {
+    safepoint();
     let gc_ptr = alloc_unmanaged(data);
     unsafe { manage(gc_ptr); }
     gc_ptr
@@ -32,34 +74,127 @@ pub fn alloc<T: Trace>(data: T) -> GcPtr<T> {
 
 /// Manage a GcPtr
 ///
-/// Invariants: ptr must not be dangling and must not already be managed
+/// Managing a GcPtr which is already managed has no effect.
+///
+/// Invariants: ptr must not be dangling
 pub unsafe fn manage<T: Trace + ?Sized>(ptr: GcPtr<T>) {
     with_gc(|gc| gc.manage(ptr))
 }
 
+/// Collect if the heap has grown past its collection threshold
+///
+/// This must only be called when every managed object that is still in use
+/// is reachable from a root.
+pub fn safepoint() {
+    with_gc(|gc| gc.safepoint())
+}
+
+/// The configuration of the current heap's collector
+pub fn config() -> GcConfig {
+    with_gc(|gc| gc.config())
+}
+
+fn configure(config: GcConfig) {
+    with_gc(|gc| gc.configure(config))
+}
+
+// Objects are allocated before the heap which will manage them is known, so
+// the current heap decides which of them are large.
+fn large_object_size() -> usize {
+    with_gc(|gc| gc.config().large_object_size)
+}
+
+/// The number of bytes in managed objects
+pub fn heap_size() -> usize {
+    with_gc(|gc| gc.pacer().heap_bytes())
+}
+
+/// The number of bytes in large objects, which are included in the heap size
+pub fn large_object_space_size() -> usize {
+    with_gc(|gc| gc.pacer().large_bytes())
+}
+
 /// Count objects managed by the GC
 pub fn count_managed_objects() -> usize {
-    with_gc(|gc| gc.objects().into_iter().count())
+    with_gc(|gc| gc.count_objects())
+}
+
+/// Count objects in the young generation
+pub fn count_young_objects() -> usize {
+    with_gc(|gc| gc.count_young_objects())
+}
+
+/// Count dead objects which are waiting to be finalized, and cleanups waiting
+/// to be run
+pub fn count_pending_finalizers() -> usize {
+    with_gc(|gc| gc.count_pending_finalizers())
 }
 
 /// Count roots into the GC
 pub fn count_roots() -> usize {
-    with_gc(|gc| gc.roots().len())
+    with_gc(|gc| gc.count_roots())
 }
 
-fn new_root() -> usize {
-    with_gc(|gc| gc.new_root())
+/// Inform the collector that a pointer is being written into a traced location
+///
+/// Any mutable container of GcPtrs must call this with both the pointer being
+/// written and the pointer being overwritten, so that an incremental collection
+/// in progress does not lose track of either of them.
+///
+/// Invariants: ptr must not be dangling
+pub unsafe fn write_barrier<T: Trace + ?Sized>(ptr: GcPtr<T>) {
+    if !ptr.is_unmanaged() {
+        heap::with_heap(ptr.heap(), |gc| gc.write_barrier(ptr))
+    }
+}
+
+/// Write a pointer into a traced location
+///
+/// Runs the write barrier for both the pointer being overwritten and the
+/// pointer being written, then performs the write while no background marker
+/// is tracing.
+///
+/// Invariants: neither ptr may be dangling, and the old ptr must be managed
+pub unsafe fn write<T: Trace + ?Sized, R, F: FnOnce() -> R>(old: GcPtr<T>, new: GcPtr<T>, f: F) -> R {
+    write_barrier(old);
+    write_barrier(new);
+    heap::with_heap(old.heap(), |gc| gc.write(f))
 }
 
-fn set_root<T: Trace + ?Sized>(idx: usize, ptr: GcPtr<T>) {
-    with_gc(|gc| gc.set_root(idx, ptr))
+unsafe fn shade<T: Trace + ?Sized>(ptr: GcPtr<T>) {
+    if !mark::shade(ptr.erased()) {
+        with_gc(|gc| gc.shade(ptr.erased()))
+    }
 }
 
-fn pop_root(idx: usize) {
-    with_gc(|gc| gc.pop_root(idx))
+fn enter<T, F: FnOnce() -> T>(gc: Option<NonNull<GcState>>, f: F) -> T {
+    struct Restore(Option<NonNull<GcState>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.with(|current| current.set(self.0));
+        }
+    }
+
+    let _restore = Restore(CURRENT.with(|current| current.replace(gc)));
+    f()
 }
 
 fn with_gc<T, F: FnOnce(Pin<&GcState>) -> T>(f: F) -> T {
+    match CURRENT.with(|current| current.get()) {
+        Some(gc)    => with_state(gc, f),
+        None        => with_default_gc(f),
+    }
+}
+
+// Heaps shared between threads are locked for the duration of the call.
+fn with_state<T, F: FnOnce(Pin<&GcState>) -> T>(gc: NonNull<GcState>, f: F) -> T {
+    let gc: Pin<&GcState> = unsafe { Pin::new_unchecked(&*gc.as_ptr()) };
+    let _guard = gc.lock();
+    f(gc)
+}
+
+fn with_default_gc<T, F: FnOnce(Pin<&GcState>) -> T>(f: F) -> T {
     GC.with(|gc| {
         let gc: Pin<&GcState> = unsafe { Pin::new_unchecked(gc) };
         f(gc)
@@ -69,3 +204,72 @@ fn with_gc<T, F: FnOnce(Pin<&GcState>) -> T>(f: F) -> T {
 pub fn collect() {
     with_gc(|gc| gc.collect())
 }
+
+/// Collect only the young generation
+///
+/// Traces from the roots and from young objects which have been written into
+/// the heap since the last collection. Surviving young objects are promoted.
+pub fn collect_minor() {
+    with_gc(|gc| gc.collect_minor())
+}
+
+/// Perform a full collection and then relocate every unpinned managed object
+///
+/// Objects referenced directly by a root, or which have been pinned, are never
+/// moved; every GcPtr traced from a managed object is updated. Objects are held
+/// in place from when a Gc pointer is derived from a field or a traced HeapRoot
+/// which points to them until the end of the next collection.
+///
+/// Invariants: no pointers into an object which may be moved can be used again,
+/// except for the GcPtrs which are traced by the collector. A Gc pointer derived
+/// from a field cannot be used again if the heap has been collected between
+/// deriving it and compacting. No thread which has not joined the heap may be
+/// reading its objects.
+pub unsafe fn compact() {
+    with_gc(|gc| gc.compact())
+}
+
+/// The number of times any heap has been compacted
+///
+/// Objects which are not pinned may have moved whenever this has changed.
+pub fn compactions() -> usize {
+    COMPACTIONS.load(Ordering::SeqCst)
+}
+
+/// Perform a bounded slice of an incremental collection
+///
+/// Traces at most `budget` objects, starting a new collection if none is in
+/// progress. Once marking is finished the heap is swept and this returns true.
+pub fn collect_step(budget: usize) -> bool {
+    with_gc(|gc| gc.collect_step(budget))
+}
+
+/// Finalize and free every dead object which is waiting to be finalized, then
+/// run the cleanups of the collected objects
+///
+/// Objects are finalized once the sweep which found them dead is finished, in
+/// the order they were found, unless the heap is configured to defer them until
+/// this is called. Returns the number of finalizers and cleanups run.
+pub fn run_finalizers() -> usize {
+    with_gc(|gc| gc.run_finalizers())
+}
+
+/// Run a function once an object has been collected
+///
+/// The function is run along with the finalizers of the collection which finds
+/// the object dead, or when its heap is dropped. It must not keep the object
+/// alive, or it will never be run. Objects in a `SyncHeap` cannot have
+/// cleanups, because the function could be run on any of its threads.
+///
+/// Invariants: ptr must not be dangling and must be managed
+pub unsafe fn on_collect<T: Trace + ?Sized>(ptr: GcPtr<T>, cleanup: Box<dyn FnOnce()>) {
+    heap::with_heap(ptr.heap(), |gc| gc.on_collect(ptr.erased(), cleanup))
+}
+
+/// Sweep a bounded number of the objects left by a lazily swept collection
+///
+/// Frees or promotes at most `budget` objects, returning true once the last
+/// collection has been swept entirely.
+pub fn sweep_step(budget: usize) -> bool {
+    with_gc(|gc| gc.sweep_step(budget))
+}
@@ -0,0 +1,400 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr::NonNull;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+use log::*;
+
+use crate::alloc::{Allocation, Data};
+
+thread_local! {
+    // The marker this thread is working for, if it is marking in parallel
+    static WORKER: Cell<Option<(*const Marker, usize)>> = Cell::new(None);
+    // The object a background marker is tracing
+    static TRACING: Cell<Option<NonNull<Allocation<Data>>>> = Cell::new(None);
+}
+
+/// The threads which help a heap's collecting thread mark it in parallel
+///
+/// They are kept for as long as the heap, parked until a marking is handed to
+/// them and again once it is finished.
+pub struct Workers {
+    pool: Arc<Pool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+struct Pool {
+    state: Mutex<PoolState>,
+    // Signalled when a marking is handed out or the pool is shut down
+    wake: Condvar,
+    // Signalled when the last worker has finished a marking
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct PoolState {
+    marker: Option<Arc<Marker>>,
+    // Counts the markings handed out, so that no worker joins one twice
+    markings: usize,
+    working: usize,
+    panic: Option<Box<dyn Any + Send>>,
+    shutdown: bool,
+}
+
+impl Workers {
+    /// Start a pool with which `threads` threads mark, counting the collecting
+    /// thread
+    pub fn new(threads: usize) -> Workers {
+        let pool = Arc::new(Pool {
+            state: Mutex::new(PoolState::default()),
+            wake: Condvar::new(),
+            done: Condvar::new(),
+        });
+        let threads = (1..threads).map(|idx| {
+            let pool = pool.clone();
+            thread::spawn(move || pool.work(idx))
+        }).collect();
+        Workers { pool, threads }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads.len() + 1
+    }
+
+    /// Trace every object reachable from the gray objects on every thread
+    ///
+    /// Each thread has its own worklist, and steals from the others once its own
+    /// is empty. When marking the young generation, old objects are not traced.
+    pub fn mark(&self, gray: Vec<NonNull<Allocation<Data>>>, young_only: bool, color: bool, log_level: LevelFilter) {
+        let threads = self.threads();
+        let marker = Arc::new(Marker::new(threads, young_only, color, log_level));
+        for (idx, object) in gray.into_iter().enumerate() {
+            marker.worklists[idx % threads].lock().unwrap().push_back(Gray(object));
+        }
+
+        {
+            let mut state = self.pool.state.lock().unwrap();
+            state.marker = Some(marker.clone());
+            state.markings += 1;
+            state.working = self.threads.len();
+        }
+        self.pool.wake.notify_all();
+
+        // This thread is the first worker
+        let result = panic::catch_unwind(AssertUnwindSafe(|| marker.work(0)));
+        let mut state = self.pool.state.lock().unwrap();
+        while state.working > 0 {
+            state = self.pool.done.wait(state).unwrap();
+        }
+        state.marker = None;
+        let panic = state.panic.take();
+        drop(state);
+
+        if let Err(err) = result {
+            panic::resume_unwind(err);
+        }
+        if let Some(err) = panic {
+            panic::resume_unwind(err);
+        }
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.pool.state.lock().unwrap().shutdown = true;
+        self.pool.wake.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Pool {
+    fn work(&self, idx: usize) {
+        let mut markings = 0;
+        loop {
+            let marker = {
+                let mut state = self.state.lock().unwrap();
+                while !state.shutdown && state.markings == markings {
+                    state = self.wake.wait(state).unwrap();
+                }
+                if state.shutdown {
+                    return;
+                }
+                markings = state.markings;
+                state.marker.clone().unwrap()
+            };
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| marker.work(idx)));
+            drop(marker);
+            let mut state = self.state.lock().unwrap();
+            if let Err(err) = result {
+                state.panic.get_or_insert(err);
+            }
+            state.working -= 1;
+            if state.working == 0 {
+                self.done.notify_all();
+            }
+        }
+    }
+}
+
+/// Shade an object on this thread's worklist, if it is a marking worker
+///
+/// Returns false if this thread is not marking in parallel.
+pub unsafe fn shade(object: NonNull<Allocation<Data>>) -> bool {
+    match WORKER.with(|worker| worker.get()) {
+        Some((marker, idx)) => {
+            (*marker).shade(idx, object);
+            true
+        }
+        None                => false,
+    }
+}
+
+/// Leave the object being traced to be traced again once the mutator has
+/// stopped, if this thread is marking in the background
+///
+/// Returns false if the object is being traced while the mutator is stopped.
+pub fn defer() -> bool {
+    match (WORKER.with(|worker| worker.get()), TRACING.with(|tracing| tracing.get())) {
+        (Some((marker, _)), Some(object))   => {
+            unsafe { (*marker).deferred.lock().unwrap().push(Gray(object)); }
+            true
+        }
+        _                                   => false,
+    }
+}
+
+/// A marker tracing on a background thread while the mutator runs
+///
+/// The mutator hands it the objects it shades, and must write to traced
+/// locations only while holding the lock returned by `lock`. Data which the
+/// mutator can change without a write barrier is deferred to the remark pause.
+pub struct Background {
+    marker: Arc<Marker>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Background {
+    pub fn start(gray: Vec<NonNull<Allocation<Data>>>, color: bool, log_level: LevelFilter) -> Background {
+        let marker = Arc::new(Marker::new(1, false, color, log_level));
+        marker.push(gray);
+
+        let thread = {
+            let marker = marker.clone();
+            thread::spawn(move || marker.work_in_background())
+        };
+        Background { marker, thread: Some(thread) }
+    }
+
+    /// Hand objects shaded by the mutator to the marker
+    pub fn push(&self, gray: Vec<NonNull<Allocation<Data>>>) {
+        if !gray.is_empty() {
+            self.marker.push(gray);
+        }
+    }
+
+    pub fn marker(&self) -> Arc<Marker> {
+        self.marker.clone()
+    }
+
+    /// Tell if the marker has run out of objects to trace
+    pub fn is_finished(&self) -> bool {
+        let _tracing = self.marker.lock();
+        self.marker.worklists[0].lock().unwrap().is_empty()
+    }
+
+    /// Stop the marker, returning the objects it had not yet traced and those
+    /// it deferred
+    pub fn stop(mut self) -> Vec<NonNull<Allocation<Data>>> {
+        self.join();
+        let worklist = self.marker.worklists[0].lock().unwrap().drain(..).map(|gray| gray.0).collect::<Vec<_>>();
+        let deferred = self.marker.deferred.lock().unwrap().drain(..).map(|gray| gray.0).collect::<Vec<_>>();
+        worklist.into_iter().chain(deferred).collect()
+    }
+
+    fn join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.marker.stopped.store(true, Ordering::SeqCst);
+            drop(self.marker.worklists[0].lock().unwrap());
+            self.marker.wake.notify_all();
+            if let Err(err) = thread.join() {
+                panic::resume_unwind(err);
+            }
+        }
+    }
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            self.join();
+        }
+    }
+}
+
+pub struct Marker {
+    worklists: Vec<Mutex<VecDeque<Gray>>>,
+    idle: AtomicUsize,
+    // Idle workers sleep on this until another worker has shaded something
+    sleeping: Mutex<()>,
+    more: Condvar,
+    panicked: AtomicBool,
+    young_only: bool,
+    color: bool,
+    log_level: LevelFilter,
+    // Held by a background marker while it traces each object
+    tracing: Mutex<()>,
+    // Objects a background marker left to be traced again in the remark pause
+    deferred: Mutex<Vec<Gray>>,
+    stopped: AtomicBool,
+    wake: Condvar,
+}
+
+struct Gray(NonNull<Allocation<Data>>);
+
+// Each object is traced by only one worker, while the mutator is stopped or
+// locked out of writing to traced locations.
+unsafe impl Send for Gray { }
+
+impl Marker {
+    fn new(threads: usize, young_only: bool, color: bool, log_level: LevelFilter) -> Marker {
+        Marker {
+            worklists: (0..threads).map(|_| Mutex::new(VecDeque::new())).collect(),
+            idle: AtomicUsize::new(0),
+            sleeping: Mutex::new(()),
+            more: Condvar::new(),
+            panicked: AtomicBool::new(false),
+            young_only,
+            color,
+            log_level,
+            tracing: Mutex::new(()),
+            deferred: Mutex::new(vec![]),
+            stopped: AtomicBool::new(false),
+            wake: Condvar::new(),
+        }
+    }
+
+    /// Prevent a background marker from tracing until the guard is dropped
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.tracing.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn push(&self, gray: Vec<NonNull<Allocation<Data>>>) {
+        self.worklists[0].lock().unwrap().extend(gray.into_iter().map(Gray));
+        self.wake.notify_all();
+    }
+
+    fn work_in_background(&self) {
+        WORKER.with(|worker| worker.set(Some((self as *const Marker, 0))));
+        let _done = Done(self);
+
+        while !self.stopped.load(Ordering::SeqCst) {
+            let tracing = self.lock();
+            let next = self.pop(0);
+            match next {
+                Some(object)    => unsafe {
+                    TRACING.with(|tracing| tracing.set(Some(object.0)));
+                    self.trace(object);
+                    TRACING.with(|tracing| tracing.set(None));
+                },
+                None            => {
+                    drop(tracing);
+                    let mut worklist = self.worklists[0].lock().unwrap();
+                    while worklist.is_empty() && !self.stopped.load(Ordering::SeqCst) {
+                        worklist = self.wake.wait(worklist).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    fn work(&self, idx: usize) {
+        WORKER.with(|worker| worker.set(Some((self as *const Marker, idx))));
+        let _done = Done(self);
+
+        loop {
+            let next = self.pop(idx).or_else(|| self.steal(idx));
+            match next {
+                Some(object)    => unsafe { self.trace(object) },
+                None            => if !self.wait() { return },
+            }
+        }
+    }
+
+    unsafe fn shade(&self, idx: usize, object: NonNull<Allocation<Data>>) {
+        if self.young_only && object.as_ref().is_old() {
+            return;
+        }
+        if object.as_ref().mark(self.color) {
+            gc_trace!(self, "MARKING object at:          {:x}", object.as_ptr() as usize);
+            if !object.as_ref().is_leaf() {
+                self.worklists[idx].lock().unwrap().push_back(Gray(object));
+                if self.idle.load(Ordering::SeqCst) > 0 {
+                    self.wake_idle();
+                }
+            }
+        }
+    }
+
+    unsafe fn trace(&self, object: Gray) {
+        gc_trace!(self, "TRACING object at:          {:x}", object.0.as_ptr() as usize);
+        object.0.as_ref().trace()
+    }
+
+    fn log_level(&self) -> LevelFilter {
+        self.log_level
+    }
+
+    fn pop(&self, idx: usize) -> Option<Gray> {
+        self.worklists[idx].lock().unwrap().pop_back()
+    }
+
+    fn steal(&self, idx: usize) -> Option<Gray> {
+        let others = self.worklists.iter().enumerate().filter(|&(other, _)| other != idx);
+        others.filter_map(|(_, worklist)| worklist.lock().unwrap().pop_front()).next()
+    }
+
+    // Wait for more work, returning false once every worker is idle. A worker
+    // only becomes idle when its own worklist is empty, and only it pushes to
+    // that worklist, so marking is finished once they are all idle.
+    fn wait(&self) -> bool {
+        self.idle.fetch_add(1, Ordering::SeqCst);
+        let mut sleeping = self.sleeping.lock().unwrap();
+        loop {
+            if self.panicked.load(Ordering::SeqCst) || self.idle.load(Ordering::SeqCst) == self.worklists.len() {
+                self.more.notify_all();
+                return false;
+            }
+            if self.worklists.iter().any(|worklist| !worklist.lock().unwrap().is_empty()) {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                return true;
+            }
+            sleeping = self.more.wait(sleeping).unwrap();
+        }
+    }
+
+    // Taking the lock first means a worker cannot miss this between finding
+    // nothing to steal and going to sleep.
+    fn wake_idle(&self) {
+        drop(self.sleeping.lock().unwrap());
+        self.more.notify_all();
+    }
+}
+
+struct Done<'a>(&'a Marker);
+
+impl<'a> Drop for Done<'a> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.panicked.store(true, Ordering::SeqCst);
+            self.0.wake_idle();
+        }
+        WORKER.with(|worker| worker.set(None));
+    }
+}
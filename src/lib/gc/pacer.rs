@@ -0,0 +1,89 @@
+use std::cell::Cell;
+
+use crate::config::GcConfig;
+
+/// Decides when allocation should trigger a collection.
+///
+/// After each collection, the next one is scheduled for when the heap has grown
+/// by the configured percentage of the bytes which survived, but never before
+/// it reaches the initial threshold or after it reaches the maximum heap size.
+pub struct Pacer {
+    threshold: Cell<usize>,
+    live_bytes: Cell<usize>,
+    heap_bytes: Cell<usize>,
+    // The part of the heap in large objects
+    large_bytes: Cell<usize>,
+    allocated_bytes: Cell<usize>,
+    allocated_objects: Cell<usize>,
+}
+
+impl Default for Pacer {
+    fn default() -> Pacer {
+        let pacer = Pacer {
+            threshold: Cell::new(0),
+            live_bytes: Cell::new(0),
+            heap_bytes: Cell::new(0),
+            large_bytes: Cell::new(0),
+            allocated_bytes: Cell::new(0),
+            allocated_objects: Cell::new(0),
+        };
+        pacer.reschedule(&GcConfig::default());
+        pacer
+    }
+}
+
+impl Pacer {
+    pub fn allocated(&self, bytes: usize) {
+        self.heap_bytes.set(self.heap_bytes.get() + bytes);
+        self.allocated_bytes.set(self.allocated_bytes.get() + bytes);
+        self.allocated_objects.set(self.allocated_objects.get() + 1);
+    }
+
+    pub fn freed(&self, bytes: usize) {
+        self.heap_bytes.set(self.heap_bytes.get() - bytes);
+    }
+
+    pub fn allocated_large(&self, bytes: usize) {
+        self.large_bytes.set(self.large_bytes.get() + bytes);
+        self.allocated(bytes);
+    }
+
+    pub fn freed_large(&self, bytes: usize) {
+        self.large_bytes.set(self.large_bytes.get() - bytes);
+        self.freed(bytes);
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.heap_bytes.get() >= self.threshold.get()
+    }
+
+    pub fn collected(&self, config: &GcConfig) {
+        self.live_bytes.set(self.heap_bytes.get());
+        self.allocated_bytes.set(0);
+        self.allocated_objects.set(0);
+        self.reschedule(config);
+    }
+
+    pub fn reschedule(&self, config: &GcConfig) {
+        let live = self.live_bytes.get();
+        let threshold = live.saturating_add(live.saturating_mul(config.growth) / 100);
+        let threshold = threshold.max(config.initial_threshold);
+        self.threshold.set(threshold.min(config.max_heap_size.unwrap_or(usize::MAX)));
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        self.heap_bytes.get()
+    }
+
+    pub fn large_bytes(&self) -> usize {
+        self.large_bytes.get()
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated_bytes.get()
+    }
+
+    pub fn allocated_objects(&self) -> usize {
+        self.allocated_objects.get()
+    }
+}
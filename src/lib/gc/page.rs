@@ -0,0 +1,485 @@
+use std::alloc::{self, Layout};
+use std::cell::{Cell as StdCell, RefCell};
+use std::mem;
+use std::ptr::{self, NonNull};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+/// The size of a page, which is also its alignment, so that the page holding
+/// any cell can be found by masking the cell's address.
+pub const PAGE_SIZE: usize = 1 << 16;
+
+// Allocations larger than the largest size class, or more aligned than every
+// cell, come straight from the system allocator.
+const SIZE_CLASSES: [usize; 14] = [16, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024, 1536, 2048];
+const CELL_ALIGN: usize = 16;
+
+// A page has a mark bit for every 8 bytes, kept in its header so that marking
+// and sweeping need not write to the objects themselves.
+const MARK_GRANULE: usize = 8;
+const MARK_WORDS: usize = PAGE_SIZE / MARK_GRANULE / 64;
+
+// Bigger objects would fill the nursery too quickly, so they are allocated
+// from the size-class pages instead.
+const NURSERY_OBJECT_SIZE: usize = 1024;
+
+static POOL: AtomicPtr<Pool> = AtomicPtr::new(ptr::null_mut());
+
+thread_local! {
+    static NURSERY: Nursery = Nursery::default();
+}
+
+/// Allocate memory for a new object, by bumping a pointer through the current
+/// thread's nursery page if the object is small enough
+pub fn alloc_young(layout: Layout) -> NonNull<u8> {
+    if layout.size() > NURSERY_OBJECT_SIZE || layout.align() > CELL_ALIGN {
+        return alloc(layout);
+    }
+    // The nursery is gone once the thread has begun to exit
+    NURSERY.try_with(|nursery| nursery.alloc(layout)).unwrap_or_else(|_| alloc(layout))
+}
+
+/// Allocate memory for a managed object
+pub fn alloc(layout: Layout) -> NonNull<u8> {
+    match size_class(layout) {
+        Some(class) => pool().classes[class].lock().unwrap().alloc(class),
+        None        => alloc_large(layout),
+    }
+}
+
+/// Allocate memory for a large object straight from the system allocator, so
+/// that it is given back as soon as the object is freed
+pub fn alloc_large(layout: Layout) -> NonNull<u8> {
+    let (layout, prefix) = with_mark_word(layout);
+    unsafe {
+        let ptr = alloc::alloc(layout);
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        ptr::write((ptr.add(prefix) as *mut AtomicU64).sub(1), AtomicU64::new(0));
+        NonNull::new_unchecked(ptr.add(prefix))
+    }
+}
+
+/// Free memory allocated by `alloc_large` with the same layout
+pub unsafe fn dealloc_large(ptr: *mut u8, layout: Layout) {
+    let (layout, prefix) = with_mark_word(layout);
+    alloc::dealloc(ptr.sub(prefix), layout)
+}
+
+/// Tell if memory for this layout is allocated from a page by `alloc`, rather
+/// than straight from the system allocator
+pub fn is_paged(layout: Layout) -> bool {
+    size_class(layout).is_some()
+}
+
+/// The mark bit of an object, as the word which holds it and a mask
+///
+/// Objects allocated from a page have their mark bits in its bitmap, and the
+/// others in a word just before them.
+pub unsafe fn mark_bit<'a>(ptr: *const u8, paged: bool) -> (&'a AtomicU64, u64) {
+    if paged {
+        let page = Page::containing(ptr as *mut u8).as_ptr();
+        let idx = (ptr as usize - page as usize) / MARK_GRANULE;
+        (&(*page).marks[idx / 64], 1 << (idx % 64))
+    } else {
+        (&*(ptr as *const AtomicU64).sub(1), 1)
+    }
+}
+
+// The layout of memory from the system allocator, with room for the mark word
+// in front of it, and the offset of the object
+fn with_mark_word(layout: Layout) -> (Layout, usize) {
+    let prefix = layout.align().max(mem::size_of::<AtomicU64>());
+    (Layout::from_size_align(layout.size() + prefix, prefix).unwrap(), prefix)
+}
+
+/// Free memory allocated by `alloc` with the same layout
+pub unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+    match size_class(layout) {
+        Some(_) if Page::is_nursery(Page::containing(ptr)) => Page::free_young(Page::containing(ptr), ptr, layout.size()),
+        Some(class) => pool().classes[class].lock().unwrap().dealloc(ptr),
+        None        => dealloc_large(ptr, layout),
+    }
+}
+
+fn size_class(layout: Layout) -> Option<usize> {
+    if layout.align() > CELL_ALIGN {
+        return None;
+    }
+    SIZE_CLASSES.iter().position(|&size| layout.size() <= size)
+}
+
+fn pool() -> &'static Pool {
+    let pool = POOL.load(Ordering::Acquire);
+    if !pool.is_null() {
+        return unsafe { &*pool };
+    }
+
+    let new = Box::into_raw(Box::new(Pool {
+        classes: SIZE_CLASSES.iter().map(|_| Mutex::new(Class::default())).collect(),
+        nursery: Mutex::new(Nurseries::default()),
+    }));
+    match POOL.compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_)       => unsafe { &*new },
+        Err(pool)   => unsafe {
+            drop(Box::from_raw(new));
+            &*pool
+        }
+    }
+}
+
+// Pages are shared by every thread, because objects allocated on one thread
+// may be freed on another.
+struct Pool {
+    classes: Vec<Mutex<Class>>,
+    nursery: Mutex<Nurseries>,
+}
+
+#[derive(Default)]
+struct Class {
+    // Pages with at least one free cell, the most recently used last
+    available: Vec<NonNull<Page>>,
+    // An empty page kept back, so that a page is not repeatedly allocated and
+    // released as the heap grows and shrinks around a page boundary
+    spare: Option<NonNull<Page>>,
+}
+
+unsafe impl Send for Class { }
+
+// Nursery pages which no thread is allocating from
+#[derive(Default)]
+struct Nurseries {
+    // An empty page kept back for the next thread which needs one
+    spare: Option<NonNull<Page>>,
+    // Pages given up by their threads, in which objects have since been freed
+    recyclable: Vec<NonNull<Page>>,
+}
+
+unsafe impl Send for Nurseries { }
+
+// A thread bumps through its nursery page until the page is full, and then
+// through the holes left by the objects freed in it since. Objects in it may be
+// freed on any thread, so the page counts its live objects atomically, with one
+// extra count held by the thread while it is allocating from the page. Whoever
+// drops the last count recycles the page.
+#[derive(Default)]
+struct Nursery {
+    page: StdCell<Option<NonNull<Page>>>,
+    // The end of the memory being bumped through, and the holes left to bump
+    // through after it, the last first
+    limit: StdCell<usize>,
+    holes: RefCell<Vec<(usize, usize)>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum NurseryState {
+    Owned,
+    // Given up by its thread, with no holes to recycle it for
+    Full,
+    // Given up by its thread, and waiting in the pool to be recycled
+    Recyclable,
+}
+
+#[repr(C)]
+struct Page {
+    // Zero for a nursery page, which holds objects of any size
+    cell_size: usize,
+    // Cells which have been freed, linked through their first word
+    free: Option<NonNull<Cell>>,
+    // The offset of the first cell which has never been allocated
+    bump: usize,
+    live: AtomicUsize,
+    available: bool,
+    // The objects freed from a nursery page, which only change state while
+    // the pool's nurseries are locked
+    holes: AtomicPtr<Hole>,
+    state: NurseryState,
+    marks: [AtomicU64; MARK_WORDS],
+}
+
+struct Cell {
+    next: Option<NonNull<Cell>>,
+}
+
+// Every object is at least as big as a hole, because its header is
+struct Hole {
+    next: *mut Hole,
+    size: usize,
+}
+
+impl Class {
+    fn alloc(&mut self, class: usize) -> NonNull<u8> {
+        let page = match self.available.last() {
+            Some(&page) => page,
+            None        => {
+                let page = self.spare.take().unwrap_or_else(|| Page::new(SIZE_CLASSES[class]));
+                unsafe { (*page.as_ptr()).available = true; }
+                self.available.push(page);
+                page
+            }
+        };
+
+        unsafe {
+            let cell = (*page.as_ptr()).alloc();
+            if (*page.as_ptr()).is_full() {
+                (*page.as_ptr()).available = false;
+                self.available.pop();
+            }
+            cell
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let page = Page::containing(ptr);
+        (*page.as_ptr()).dealloc(ptr);
+
+        if *(*page.as_ptr()).live.get_mut() == 0 {
+            // Keep the empty page as the spare, or return it to the system allocator
+            if let Some(idx) = self.available.iter().position(|&available| available == page) {
+                self.available.remove(idx);
+            }
+            (*page.as_ptr()).available = false;
+            match self.spare {
+                None    => self.spare = Some(page),
+                Some(_) => Page::release(page),
+            }
+        } else if !(*page.as_ptr()).available {
+            (*page.as_ptr()).available = true;
+            self.available.push(page);
+        }
+    }
+}
+
+impl Nursery {
+    fn alloc(&self, layout: Layout) -> NonNull<u8> {
+        loop {
+            let page = match self.page.get() {
+                Some(page)  => page,
+                None        => {
+                    let page = self.acquire();
+                    self.page.set(Some(page));
+                    page
+                }
+            };
+
+            unsafe {
+                if let Some(ptr) = Page::bump(page, layout, self.limit.get()) {
+                    return ptr;
+                }
+
+                // A full page is bumped through again from the start if
+                // everything allocated in it has been freed, and otherwise
+                // through its holes. Without any, it is left to its objects.
+                if (*page.as_ptr()).live.load(Ordering::Acquire) == 1 {
+                    (*page.as_ptr()).holes.store(ptr::null_mut(), Ordering::Relaxed);
+                    self.holes.borrow_mut().clear();
+                    self.set_bounds(page, Page::first_cell(), PAGE_SIZE);
+                } else if let Some((start, end)) = self.next_hole(page) {
+                    self.set_bounds(page, start, end);
+                } else {
+                    self.page.set(None);
+                    Page::retire(page);
+                }
+            }
+        }
+    }
+
+    // Take a page which another thread has given up if there is one, so that
+    // the holes in it are not wasted, and an empty one otherwise
+    fn acquire(&self) -> NonNull<Page> {
+        self.holes.borrow_mut().clear();
+        let mut nurseries = pool().nursery.lock().unwrap();
+        while let Some(page) = nurseries.recyclable.pop() {
+            unsafe {
+                (*page.as_ptr()).state = NurseryState::Owned;
+                // The last of its objects may be being freed, in which case
+                // the page is about to be made the spare
+                let live = &(*page.as_ptr()).live;
+                if live.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| if n == 0 { None } else { Some(n + 1) }).is_ok() {
+                    self.set_bounds(page, PAGE_SIZE, PAGE_SIZE);
+                    return page;
+                }
+            }
+        }
+
+        let page = nurseries.spare.take().unwrap_or_else(|| Page::new(0));
+        unsafe {
+            (*page.as_ptr()).holes.store(ptr::null_mut(), Ordering::Relaxed);
+            (*page.as_ptr()).state = NurseryState::Owned;
+            (*page.as_ptr()).live.store(1, Ordering::Relaxed);
+            self.set_bounds(page, Page::first_cell(), PAGE_SIZE);
+        }
+        page
+    }
+
+    // The next hole to bump through, taking the objects freed since the last
+    // holes were taken once those are used up
+    unsafe fn next_hole(&self, page: NonNull<Page>) -> Option<(usize, usize)> {
+        let mut holes = self.holes.borrow_mut();
+        if holes.is_empty() {
+            let mut hole = (*page.as_ptr()).holes.swap(ptr::null_mut(), Ordering::Acquire);
+            while !hole.is_null() {
+                let start = hole as usize - page.as_ptr() as usize;
+                holes.push((start, start + (*hole).size));
+                hole = (*hole).next;
+            }
+            // Neighbouring holes are merged into one
+            holes.sort_unstable_by(|a, b| b.cmp(a));
+            holes.dedup_by(|lower, higher| {
+                let adjacent = lower.1 == higher.0;
+                if adjacent {
+                    higher.0 = lower.0;
+                }
+                adjacent
+            });
+        }
+        holes.pop()
+    }
+
+    unsafe fn set_bounds(&self, page: NonNull<Page>, start: usize, end: usize) {
+        (*page.as_ptr()).bump = start;
+        self.limit.set(end);
+    }
+}
+
+impl Drop for Nursery {
+    fn drop(&mut self) {
+        if let Some(page) = self.page.get() {
+            unsafe { Page::retire(page) }
+        }
+    }
+}
+
+impl Page {
+    fn new(cell_size: usize) -> NonNull<Page> {
+        let layout = Page::layout();
+        unsafe {
+            let page = alloc::alloc(layout) as *mut Page;
+            if page.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            ptr::write(page, Page {
+                cell_size,
+                free: None,
+                bump: Page::first_cell(),
+                live: AtomicUsize::new(0),
+                available: false,
+                holes: AtomicPtr::new(ptr::null_mut()),
+                state: NurseryState::Owned,
+                marks: mem::zeroed(),
+            });
+            NonNull::new_unchecked(page)
+        }
+    }
+
+    unsafe fn release(page: NonNull<Page>) {
+        alloc::dealloc(page.as_ptr() as *mut u8, Page::layout());
+    }
+
+    fn containing(ptr: *mut u8) -> NonNull<Page> {
+        unsafe { NonNull::new_unchecked((ptr as usize & !(PAGE_SIZE - 1)) as *mut Page) }
+    }
+
+    unsafe fn is_nursery(page: NonNull<Page>) -> bool {
+        (*page.as_ptr()).cell_size == 0
+    }
+
+    // Nursery pages are only ever accessed through raw pointers, because other
+    // threads may be freeing their objects at the same time.
+    unsafe fn bump(page: NonNull<Page>, layout: Layout, limit: usize) -> Option<NonNull<u8>> {
+        let page = page.as_ptr();
+        let start = ((*page).bump + layout.align() - 1) & !(layout.align() - 1);
+        if start + layout.size() > limit {
+            return None;
+        }
+        (*page).bump = start + layout.size();
+        (*page).live.fetch_add(1, Ordering::Relaxed);
+        Some(NonNull::new_unchecked((page as *mut u8).add(start)))
+    }
+
+    unsafe fn free_young(page: NonNull<Page>, ptr: *mut u8, size: usize) {
+        let hole = ptr as *mut Hole;
+        ptr::write(hole, Hole { next: ptr::null_mut(), size });
+        let holes = &(*page.as_ptr()).holes;
+        let mut head = holes.load(Ordering::Relaxed);
+        loop {
+            (*hole).next = head;
+            match holes.compare_exchange_weak(head, hole, Ordering::Release, Ordering::Relaxed) {
+                Ok(_)       => break,
+                Err(next)   => head = next,
+            }
+        }
+
+        // A page given up while full can be recycled once it has a hole
+        if head.is_null() {
+            let mut nurseries = pool().nursery.lock().unwrap();
+            if (*page.as_ptr()).state == NurseryState::Full {
+                (*page.as_ptr()).state = NurseryState::Recyclable;
+                nurseries.recyclable.push(page);
+            }
+        }
+        Page::decrement(page);
+    }
+
+    // Give up a nursery page which its thread is done allocating from
+    unsafe fn retire(page: NonNull<Page>) {
+        {
+            let mut nurseries = pool().nursery.lock().unwrap();
+            if (*page.as_ptr()).holes.load(Ordering::Relaxed).is_null() {
+                (*page.as_ptr()).state = NurseryState::Full;
+            } else {
+                (*page.as_ptr()).state = NurseryState::Recyclable;
+                nurseries.recyclable.push(page);
+            }
+        }
+        Page::decrement(page);
+    }
+
+    unsafe fn decrement(page: NonNull<Page>) {
+        if (*page.as_ptr()).live.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let mut nurseries = pool().nursery.lock().unwrap();
+            if (*page.as_ptr()).state == NurseryState::Recyclable {
+                nurseries.recyclable.retain(|&recyclable| recyclable != page);
+            }
+            match nurseries.spare {
+                None    => nurseries.spare = Some(page),
+                Some(_) => Page::release(page),
+            }
+        }
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+
+    fn first_cell() -> usize {
+        (mem::size_of::<Page>() + CELL_ALIGN - 1) & !(CELL_ALIGN - 1)
+    }
+
+    fn is_full(&self) -> bool {
+        self.free.is_none() && self.bump + self.cell_size > PAGE_SIZE
+    }
+
+    unsafe fn alloc(&mut self) -> NonNull<u8> {
+        *self.live.get_mut() += 1;
+        match self.free {
+            Some(cell)  => {
+                self.free = (*cell.as_ptr()).next;
+                cell.cast()
+            }
+            None        => {
+                let cell = (self as *mut Page as *mut u8).add(self.bump);
+                self.bump += self.cell_size;
+                NonNull::new_unchecked(cell)
+            }
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        *self.live.get_mut() -= 1;
+        let cell = ptr as *mut Cell;
+        ptr::write(cell, Cell { next: self.free });
+        self.free = Some(NonNull::new_unchecked(cell));
+    }
+}
@@ -1,22 +1,54 @@
+use std::pin::Pin;
+use std::ptr::NonNull;
+
 use crate::gc_ptr::GcPtr;
+use crate::heap::{self, Enter};
+use crate::state::GcState;
 use crate::trace::Trace;
 
 pub struct Root {
     idx: usize,
+    heap: NonNull<GcState>,
 }
 
 impl Root {
+    /// Create a root in the current heap
     pub fn new() -> Root {
-        Root { idx: super::new_root() }
+        super::with_gc(Root::in_state)
+    }
+
+    /// Create a root in a particular heap
+    pub fn new_in<H: Enter>(heap: &H) -> Root {
+        heap.enter(Root::new)
+    }
+
+    /// Create a root in the heap which manages an object
+    ///
+    /// Invariants: ptr must not be dangling and must be managed
+    pub unsafe fn new_for<T: ?Sized>(ptr: GcPtr<T>) -> Root {
+        heap::with_heap(ptr.heap(), Root::in_state)
+    }
+
+    fn in_state(gc: Pin<&GcState>) -> Root {
+        Root { idx: gc.new_root(), heap: NonNull::from(&*gc) }
     }
 
     pub unsafe fn enroot<T: Trace + ?Sized>(&self, gc_ptr: GcPtr<T>) {
-        super::set_root(self.idx, gc_ptr)
+        super::with_state(self.heap, |gc| gc.set_root(self.idx, gc_ptr))
+    }
+
+    /// Run a function with the heap this root belongs to as the current heap
+    ///
+    /// If the heap is shared between threads, it stays locked until the
+    /// function returns.
+    pub fn enter<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        super::enter(Some(self.heap), || super::with_state(self.heap, |_| f()))
     }
 }
 
 impl Drop for Root {
     fn drop(&mut self) {
-        super::pop_root(self.idx);
+        // Heaps are never freed while they still have roots
+        super::with_state(self.heap, |gc| gc.pop_root(self.idx));
     }
 }
@@ -0,0 +1,55 @@
+use crate::gc_ptr::GcPtr;
+use crate::heap;
+
+/// A reference to a managed object which keeps it alive until the heap's
+/// configured policy gives it up
+///
+/// Once given up, the reference is cleared like a weak reference by the
+/// collection which finds the object dead.
+pub struct Soft<T: ?Sized> {
+    idx: usize,
+    heap: u32,
+    // Only the address of the object is kept by the heap
+    ptr: GcPtr<T>,
+}
+
+impl<T: ?Sized> Soft<T> {
+    /// Invariants: ptr must not be dangling and must be managed
+    pub unsafe fn new(ptr: GcPtr<T>) -> Soft<T> {
+        let heap = ptr.heap();
+        let idx = heap::with_heap(heap, |gc| gc.new_soft(Some(ptr.erased())));
+        Soft { idx, heap, ptr }
+    }
+
+    /// The object this refers to, unless it has been collected, counting it as
+    /// used
+    ///
+    /// The object must be rooted before the heap is next collected.
+    pub fn get(&self) -> Option<GcPtr<T>> {
+        self.lookup(true)
+    }
+
+    /// The object this refers to, without counting it as used
+    pub fn peek(&self) -> Option<GcPtr<T>> {
+        self.lookup(false)
+    }
+
+    fn lookup(&self, used: bool) -> Option<GcPtr<T>> {
+        let object = heap::try_with_heap(self.heap, |gc| gc.soft(self.idx, used));
+        object.and_then(|object| object).map(|object| self.ptr.with_address(object))
+    }
+}
+
+impl<T: ?Sized> Clone for Soft<T> {
+    fn clone(&self) -> Soft<T> {
+        let idx = heap::with_heap(self.heap, |gc| gc.new_soft(gc.soft(self.idx, false)));
+        Soft { idx, ..*self }
+    }
+}
+
+impl<T: ?Sized> Drop for Soft<T> {
+    fn drop(&mut self) {
+        // The heap may already have been dropped, along with its soft references
+        heap::try_with_heap(self.heap, |gc| gc.drop_soft(self.idx));
+    }
+}
@@ -1,74 +1,1049 @@
-use std::cell::{Ref, RefCell};
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::{HashSet, VecDeque};
+use std::mem;
 use std::pin::Pin;
 use std::ptr::NonNull;
+use std::sync::atomic::Ordering;
 
 use log::*;
 
 use crate::alloc::{Allocation, Data};
+use crate::config::GcConfig;
 use crate::gc_ptr::GcPtr;
-use crate::list::List;
+use crate::mark::{Background, Workers};
+use crate::pacer::Pacer;
+use crate::sync::{Guard, SyncState};
 use crate::trace::Trace;
 
 #[derive(Default)]
 pub struct GcState {
-    objects: List<Allocation<Data>>,
+    id: u32,
+    // Every object the heap manages is in one of these tables, rather than
+    // being linked through its header.
+    young: RefCell<Vec<NonNull<Allocation<Data>>>>,
+    old: RefCell<Vec<NonNull<Allocation<Data>>>>,
+    // The size of the young objects which have joined this heap since its young
+    // generation was last collected, whichever threads allocated them
+    young_bytes: Cell<usize>,
+    // Large objects are promoted by the first collection which sees them, and
+    // are swept at once by every collection
+    large: RefCell<Vec<NonNull<Allocation<Data>>>>,
+    // Objects left by the last collection for a lazy sweep to free or promote
+    unswept_young: RefCell<Vec<NonNull<Allocation<Data>>>>,
+    unswept_old: RefCell<Vec<NonNull<Allocation<Data>>>>,
+    sweep_pending: Cell<bool>,
+    // Dead objects with finalizers, which are only finalized once the sweep
+    // which found them is finished
+    finalize_queue: RefCell<VecDeque<NonNull<Allocation<Data>>>>,
+    finalizing: Cell<bool>,
+    // Functions to run once their objects have been collected, which are queued
+    // along with the objects to finalize
+    cleanups: RefCell<Vec<(NonNull<Allocation<Data>>, Box<dyn FnOnce()>)>>,
+    cleanup_queue: RefCell<VecDeque<Box<dyn FnOnce()>>>,
+    guardians: RefCell<Vec<Option<Guarded>>>,
+    free_guardians: RefCell<Vec<usize>>,
+    // An object is marked when its mark bit is equal to the heap's color. The
+    // color flips once a major collection has been swept, unmarking every
+    // survivor at once; a minor collection unmarks its few survivors instead.
+    color: Cell<bool>,
+    flip_pending: Cell<bool>,
+    remembered: RefCell<HashSet<NonNull<Allocation<Data>>>>,
+    // Objects which Gc pointers have been derived to since the last collection,
+    // and which are not moved until it is over
+    held: RefCell<Vec<NonNull<Allocation<Data>>>>,
+    compacting: Cell<bool>,
     roots: RefCell<Vec<Option<NonNull<Allocation<Data>>>>>,
+    free_roots: RefCell<Vec<usize>>,
+    // Weak references are cleared once their objects are found to be dead, and
+    // their slots are reused like those of roots.
+    weaks: RefCell<Vec<Option<NonNull<Allocation<Data>>>>>,
+    free_weaks: RefCell<Vec<usize>>,
+    // Soft references are strong until the configured policy gives them up
+    softs: RefCell<Vec<Option<Soft>>>,
+    free_softs: RefCell<Vec<usize>>,
+    // The value of an ephemeron is only traced once its key has been marked
+    ephemerons: RefCell<Vec<Option<(NonNull<Allocation<Data>>, NonNull<Allocation<Data>>)>>>,
+    free_ephemerons: RefCell<Vec<usize>>,
+    gray: RefCell<Vec<NonNull<Allocation<Data>>>>,
+    unmanaged: RefCell<Vec<NonNull<Allocation<Data>>>>,
+    managing: Cell<bool>,
+    phase: Cell<Phase>,
+    pacer: Pacer,
+    config: Cell<GcConfig>,
+    sync: Option<SyncState>,
+    background: RefCell<Option<Background>>,
+    workers: RefCell<Option<Workers>>,
+}
+
+struct Soft {
+    object: NonNull<Allocation<Data>>,
+    // The number of major collections begun since it was last used
+    idle: usize,
+}
+
+// The objects registered with a guardian. Once a registered object is found
+// to be otherwise unreachable, it is kept alive and queued as ready until the
+// guardian hands it back.
+#[derive(Default)]
+struct Guarded {
+    registered: Vec<NonNull<Allocation<Data>>>,
+    ready: VecDeque<NonNull<Allocation<Data>>>,
+}
+
+// The tri-color invariant: white objects are unmarked, gray objects are marked
+// but still on the gray stack, black objects are marked and have been traced.
+// While marking, no black object may point to a white object.
+//
+// A minor collection only marks the young generation: old objects are treated
+// as black, and every young object which has been written into a traced
+// location since the last collection is remembered as an additional root.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Phase {
+    Idle,
+    Marking,
+    MarkingYoung,
+    Sweeping,
+}
+
+impl Default for Phase {
+    fn default() -> Phase {
+        Phase::Idle
+    }
 }
 
 impl GcState {
+    pub fn new(id: u32) -> GcState {
+        GcState { id, ..GcState::default() }
+    }
+
+    pub fn new_sync(id: u32) -> GcState {
+        GcState { id, sync: Some(SyncState::default()), ..GcState::default() }
+    }
+
     pub fn collect(self: Pin<&Self>) {
+        self.collect_step(usize::MAX);
+    }
+
+    pub fn collect_step(self: Pin<&Self>, budget: usize) -> bool {
+        // Called from a finalizer while the heap is torn down
+        if self.phase.get() == Phase::Sweeping {
+            return false;
+        }
+
+        let _world = self.stop_world();
+        if self.phase.get() == Phase::Idle {
+            self.begin_marking();
+        }
+
+        // Marking in the background is finished with a short remark pause, once
+        // the marker has run out of work or a full collection is requested.
+        if self.background.borrow().is_some() {
+            if budget != usize::MAX && !self.hand_off() {
+                return false;
+            }
+            self.stop_background();
+            self.shade_roots();
+        }
+
+        if self.finish_marking(budget) {
+            self.clear_weaks();
+            self.sweep();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Collect if enough has been allocated since the last collection
+    pub fn safepoint(self: Pin<&Self>) {
+        if self.phase.get() == Phase::Sweeping {
+            return;
+        }
+
+        let config = self.config.get();
+        if self.sweep_pending.get() {
+            self.sweep_step(config.lazy_sweep);
+        }
+
+        if self.background.borrow().is_some() {
+            if self.hand_off() {
+                gc_debug!(self, "REMARKING after marking in the background");
+                self.collect();
+            }
+        } else if config.stress || self.pacer.should_collect() {
+            gc_debug!(self, "COLLECTING after allocating {} objects ({} bytes)",
+                self.pacer.allocated_objects(), self.pacer.allocated_bytes());
+            if config.concurrent && self.phase.get() == Phase::Idle {
+                self.begin_background_marking();
+            } else {
+                self.collect();
+            }
+        } else if self.nursery_full(&config) {
+            gc_debug!(self, "COLLECTING the young generation after filling the nursery");
+            self.collect_minor();
+        }
+
+        if let Some(max) = config.max_heap_size {
+            if self.pacer.heap_bytes() > max {
+                self.check_heap_size(max);
+            }
+        }
+    }
+
+    // The heap may still hold dead objects which have not been swept or
+    // finalized, so it is only found too large once a full collection has been
+    // finished and every dead object it found has been freed.
+    fn check_heap_size(self: Pin<&Self>, max: usize) {
+        // A collection in progress may have begun before the garbage was
+        // allocated, so it is finished before collecting again from scratch.
+        if self.phase.get() != Phase::Idle || self.background.borrow().is_some() {
+            self.collect();
+        }
+        self.collect();
+        self.run_finalizers();
+
+        // Finalizers which are already being run cannot be run from here
+        let live = self.pacer.heap_bytes();
+        if live > max && self.finalize_queue.borrow().is_empty() {
+            panic!("managed heap of {} bytes exceeds the maximum heap size of {} bytes", live, max);
+        }
+    }
+
+    // A full nursery is only collected between major collections, which
+    // collect the young generation anyway.
+    fn nursery_full(&self, config: &GcConfig) -> bool {
+        config.nursery_size != 0
+            && self.young_bytes.get() >= config.nursery_size
+            && self.phase.get() == Phase::Idle
+            && self.background.borrow().is_none()
+    }
+
+    pub fn collect_minor(self: Pin<&Self>) {
+        // A minor collection cannot run in the middle of an incremental major
+        // collection, so finish that one instead.
+        if self.phase.get() != Phase::Idle {
+            return self.collect();
+        }
+
+        let _world = self.stop_world();
+        self.finish_sweep();
+        self.phase.set(Phase::MarkingYoung);
+        self.shade_roots();
+        self.shade_softs();
+        let remembered: Vec<_> = self.remembered.borrow_mut().drain().collect();
+        for object in remembered {
+            gc_trace!(self, "TRACING from remembered:    {:x}", object.as_ptr() as usize);
+            unsafe {
+                self.shade(object);
+            }
+        }
+        self.finish_marking(usize::MAX);
+        self.clear_weaks();
+        self.let_go_held();
+        self.sweep_large();
+        self.sweep_young();
+    }
+
+    pub unsafe fn compact(self: Pin<&Self>) {
+        self.compacting.set(true);
+        self.collect();
+        self.compacting.set(false);
+        let _world = self.stop_world();
+        self.finish_sweep();
+
+        // Objects referenced by roots are never moved, because the Gc pointers
+        // derived from those roots point directly at them.
+        let rooted: HashSet<_> = self.roots().iter().filter_map(|root| *root).collect();
+
+        let mut moved = vec![];
+        for objects in &[&self.young, &self.old] {
+            for object in objects.borrow_mut().iter_mut() {
+                let ptr = *object;
+                if ptr.as_ref().is_pinned() || ptr.as_ref().is_held() || rooted.contains(&ptr) {
+                    continue;
+                }
+                let (new, layout) = (ptr.as_ptr()).relocate();
+                gc_trace!(self, "RELOCATING object at:       {:x} to {:x}", ptr.as_ptr() as usize, new.as_ptr() as usize);
+                self.unmark(new.as_ref());
+                *object = new;
+                moved.push((ptr, layout));
+            }
+        }
+
+        for object in self.objects() {
+            object.as_ptr().relocate_pointers();
+        }
+        for weak in self.weaks.borrow_mut().iter_mut() {
+            if let Some(new) = weak.and_then(|object| object.as_ref().forwarded()) {
+                *weak = Some(new);
+            }
+        }
+        for soft in self.softs.borrow_mut().iter_mut().flatten() {
+            if let Some(new) = soft.object.as_ref().forwarded() {
+                soft.object = new;
+            }
+        }
+        for (object, _) in self.cleanups.borrow_mut().iter_mut() {
+            if let Some(new) = object.as_ref().forwarded() {
+                *object = new;
+            }
+        }
+        for guarded in self.guardians.borrow_mut().iter_mut().flatten() {
+            for object in guarded.registered.iter_mut().chain(guarded.ready.iter_mut()) {
+                if let Some(new) = object.as_ref().forwarded() {
+                    *object = new;
+                }
+            }
+        }
+        for ephemeron in self.ephemerons.borrow_mut().iter_mut().filter_map(|e| e.as_mut()) {
+            for object in &mut [&mut ephemeron.0, &mut ephemeron.1] {
+                if let Some(new) = object.as_ref().forwarded() {
+                    **object = new;
+                }
+            }
+        }
+
+        for (ptr, layout) in moved {
+            ptr.as_ptr().release(layout);
+        }
+        for object in self.held.borrow_mut().drain(..) {
+            object.as_ref().let_go();
+        }
+        crate::COMPACTIONS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn begin_background_marking(self: Pin<&Self>) {
+        let _world = self.stop_world();
+        self.begin_marking();
+        let gray = mem::replace(&mut *self.gray.borrow_mut(), vec![]);
+        *self.background.borrow_mut() = Some(Background::start(gray, self.color.get(), self.log_level()));
+    }
+
+    // Give the objects shaded by the mutator to the background marker, returning
+    // true if it has run out of work.
+    fn hand_off(self: Pin<&Self>) -> bool {
+        let gray = mem::replace(&mut *self.gray.borrow_mut(), vec![]);
+        let background = self.background.borrow();
+        let background = background.as_ref().unwrap();
+        background.push(gray);
+        background.is_finished()
+    }
+
+    fn stop_background(self: Pin<&Self>) {
+        let background = self.background.borrow_mut().take();
+        if let Some(background) = background {
+            let gray = background.stop();
+            self.gray.borrow_mut().extend(gray);
+        }
+    }
+
+    fn begin_marking(self: Pin<&Self>) {
+        self.finish_sweep();
+        self.phase.set(Phase::Marking);
+        self.shade_roots();
+        self.shade_softs();
+    }
+
+    fn shade_roots(self: Pin<&Self>) {
         for (idx, root) in self.roots()[..].iter().enumerate() {
             if let Some(root) = root {
-                debug!("TRACING from root at:       {:x} (idx {:x})", &*root as *const _ as usize, idx);
+                gc_trace!(self, "TRACING from root at:       {:x} (idx {:x})", root.as_ptr() as usize, idx);
                 unsafe {
-                    root.as_ref().mark();
+                    self.shade(*root);
                 }
             }
         }
+        // Objects waiting for their guardians to hand them back are roots
+        for object in self.guardians.borrow().iter().flatten().flat_map(|guarded| &guarded.ready) {
+            unsafe {
+                self.shade(*object);
+            }
+        }
+    }
 
-        for object in self.objects() {
-            if !object.marked() {
-                debug!("FREEING unmarked object at: {:x}", &*object as *const _ as usize);
+    // Shade the objects behind the soft references which the configured policy
+    // keeps for another major collection. A minor collection keeps all of them,
+    // and does not count as a collection for which they have gone unused.
+    fn shade_softs(self: Pin<&Self>) {
+        let config = self.config.get();
+        let heap_bytes = self.pacer.heap_bytes();
+        let major = self.phase.get() == Phase::Marking;
+        for soft in self.softs.borrow_mut().iter_mut().flatten() {
+            if major {
+                soft.idle += 1;
+            }
+            if !major || config.keep_soft(heap_bytes, soft.idle) {
                 unsafe {
-                    (&*object as *const Allocation<Data> as *mut Allocation<Data>).free();
+                    self.shade(soft.object);
                 }
             }
         }
     }
 
-    pub unsafe fn manage<T: Trace + ?Sized>(self: Pin<&Self>, ptr: GcPtr<T>) {
-        // TODO I should not need a dynamic check here but I am making mistakes
+    // Trace up to `budget` gray objects, returning true once none are left. An
+    // unbounded mark is split across the configured number of threads.
+    fn mark(self: Pin<&Self>, budget: usize) -> bool {
+        let threads = self.config.get().mark_threads;
+        if budget == usize::MAX && threads > 1 {
+            let gray = mem::replace(&mut *self.gray.borrow_mut(), vec![]);
+            let mut workers = self.workers.borrow_mut();
+            if workers.as_ref().map_or(true, |workers| workers.threads() != threads) {
+                // The old pool is shut down before the new one is started
+                *workers = None;
+                *workers = Some(Workers::new(threads));
+            }
+            let workers = workers.as_ref().unwrap();
+            workers.mark(gray, self.phase.get() == Phase::MarkingYoung, self.color.get(), self.log_level());
+            return true;
+        }
+
+        for _ in 0..budget {
+            let next = self.gray.borrow_mut().pop();
+            match next {
+                Some(object)    => unsafe {
+                    gc_trace!(self, "TRACING object at:          {:x}", object.as_ptr() as usize);
+                    object.as_ref().trace()
+                },
+                None            => return true,
+            }
+        }
+        self.gray.borrow().is_empty()
+    }
+
+    // Trace everything reachable, and then everything reachable from the guarded
+    // objects found unreachable, `budget` gray objects at a time. The guarded
+    // objects are kept alive before any weak reference is cleared, so weak
+    // references to them survive along with them.
+    fn finish_marking(self: Pin<&Self>, budget: usize) -> bool {
+        loop {
+            if !self.mark_ephemerons(budget) {
+                return false;
+            }
+            if !self.resurrect_guarded() {
+                return true;
+            }
+        }
+    }
+
+    // Queue every guarded object which has not been marked on its guardian and
+    // shade it, returning true if there were any.
+    fn resurrect_guarded(self: Pin<&Self>) -> bool {
+        let mut resurrected = vec![];
+        for guarded in self.guardians.borrow_mut().iter_mut().flatten() {
+            let registered = mem::replace(&mut guarded.registered, vec![]);
+            for object in registered {
+                if self.is_live(unsafe { object.as_ref() }) {
+                    guarded.registered.push(object);
+                } else {
+                    gc_trace!(self, "RESURRECTING object at:     {:x}", object.as_ptr() as usize);
+                    guarded.ready.push_back(object);
+                    resurrected.push(object);
+                }
+            }
+        }
+        // Objects reachable only from other guarded objects are queued as well,
+        // so nothing is shaded until every guardian has been examined.
+        for object in &resurrected {
+            unsafe {
+                self.shade(*object);
+            }
+        }
+        !resurrected.is_empty()
+    }
+
+    // Trace until no object is gray and the value of every ephemeron with a
+    // marked key has been marked, `budget` gray objects at a time.
+    fn mark_ephemerons(self: Pin<&Self>, budget: usize) -> bool {
+        while self.mark(budget) {
+            if !self.shade_ephemerons() {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Shade the value of every ephemeron with a marked key, returning true if
+    // any of them were white.
+    fn shade_ephemerons(self: Pin<&Self>) -> bool {
+        let mut shaded = false;
+        for &(key, value) in self.ephemerons.borrow().iter().flatten() {
+            unsafe {
+                if self.is_live(key.as_ref()) && !self.is_live(value.as_ref()) {
+                    gc_trace!(self, "TRACING from ephemeron:     {:x}", key.as_ptr() as usize);
+                    self.shade(value);
+                    shaded = true;
+                }
+            }
+        }
+        shaded
+    }
+
+    // Once marking is finished, clear every weak reference and ephemeron whose
+    // object is about to be freed.
+    fn clear_weaks(&self) {
+        for weak in self.weaks.borrow_mut().iter_mut() {
+            if let Some(object) = *weak {
+                if !self.is_live(unsafe { object.as_ref() }) {
+                    gc_trace!(self, "CLEARING weak reference to: {:x}", object.as_ptr() as usize);
+                    *weak = None;
+                }
+            }
+        }
+        for soft in self.softs.borrow_mut().iter_mut() {
+            if let Some(Soft { object, .. }) = *soft {
+                if !self.is_live(unsafe { object.as_ref() }) {
+                    gc_trace!(self, "CLEARING soft reference to: {:x}", object.as_ptr() as usize);
+                    *soft = None;
+                }
+            }
+        }
+        for ephemeron in self.ephemerons.borrow_mut().iter_mut() {
+            if let Some((key, _)) = *ephemeron {
+                if !self.is_live(unsafe { key.as_ref() }) {
+                    gc_trace!(self, "CLEARING ephemeron of:      {:x}", key.as_ptr() as usize);
+                    *ephemeron = None;
+                }
+            }
+        }
+        let cleanups = mem::replace(&mut *self.cleanups.borrow_mut(), vec![]);
+        for (object, cleanup) in cleanups {
+            if self.is_live(unsafe { object.as_ref() }) {
+                self.cleanups.borrow_mut().push((object, cleanup));
+            } else {
+                self.cleanup_queue.borrow_mut().push_back(cleanup);
+            }
+        }
+    }
+
+    // Whether an object will survive the collection being marked, which leaves
+    // the old generation alone if it is a minor collection
+    fn is_live(&self, object: &Allocation<Data>) -> bool {
+        (self.phase.get() == Phase::MarkingYoung && object.is_old()) || object.is_marked(self.color.get())
+    }
+
+    // Once marking is over, and before anything is freed, let go of the objects
+    // held since the last collection. A compaction keeps holding those which
+    // are still alive until it has moved the others.
+    fn let_go_held(&self) {
+        let held = mem::replace(&mut *self.held.borrow_mut(), vec![]);
+        for object in held {
+            unsafe {
+                if !self.compacting.get() {
+                    object.as_ref().let_go();
+                } else if self.is_live(object.as_ref()) {
+                    self.held.borrow_mut().push(object);
+                }
+            }
+        }
+    }
+
+    fn sweep(self: Pin<&Self>) {
+        self.let_go_held();
+        self.flip_pending.set(true);
+        self.sweep_large();
+        // The old survivors are put back in a table with room for all of them
+        let capacity = self.old.borrow().len();
+        let old = mem::replace(&mut *self.old.borrow_mut(), Vec::with_capacity(capacity));
+        *self.unswept_old.borrow_mut() = old;
+        self.sweep_young();
+    }
+
+    // However the rest of the heap is swept, large objects are freed as soon as
+    // they are found dead. They are young until the first collection which sees
+    // them, so that a minor collection traces the young objects they point to.
+    fn sweep_large(self: Pin<&Self>) {
+        let young_only = self.phase.get() == Phase::MarkingYoung;
+        let phase = self.phase.replace(Phase::Sweeping);
+        let large = mem::replace(&mut *self.large.borrow_mut(), vec![]);
+        for object in large {
+            unsafe {
+                if (young_only && object.as_ref().is_old()) || object.as_ref().is_marked(self.color.get()) {
+                    if !object.as_ref().is_old() {
+                        object.as_ref().promote();
+                        if !self.flip_pending.get() {
+                            object.as_ref().unmark(self.color.get());
+                        }
+                    }
+                    self.large.borrow_mut().push(object);
+                } else {
+                    self.free(object)
+                }
+            }
+        }
+        self.phase.set(phase);
+    }
+
+    // Leave the young generation empty, and sweep everything which was marked
+    // now or later, depending on the configuration.
+    fn sweep_young(self: Pin<&Self>) {
+        mem::swap(&mut *self.unswept_young.borrow_mut(), &mut *self.young.borrow_mut());
+        self.remembered.borrow_mut().clear();
+        self.young_bytes.set(0);
+        self.sweep_pending.set(true);
+        self.phase.set(Phase::Idle);
+
+        if self.config.get().lazy_sweep == 0 {
+            self.sweep_step(usize::MAX);
+        } else {
+            gc_debug!(self, "MARKED leaving the heap to be swept lazily");
+        }
+    }
+
+    /// Sweep up to `budget` objects left by the last collection, freeing the
+    /// dead objects and promoting every survivor. Returns true once none are left.
+    pub fn sweep_step(self: Pin<&Self>, budget: usize) -> bool {
+        // Called from a finalizer while the heap is torn down
+        if self.phase.get() == Phase::Sweeping {
+            return false;
+        }
+        if !self.sweep_pending.get() {
+            return true;
+        }
+
+        self.phase.set(Phase::Sweeping);
+        for _ in 0..budget {
+            let old = self.unswept_old.borrow_mut().pop();
+            if let Some(object) = old {
+                unsafe { self.sweep_old(object) }
+                continue;
+            }
+            let young = self.unswept_young.borrow_mut().pop();
+            match young {
+                Some(object)    => unsafe { self.sweep_young_object(object) },
+                None            => break,
+            }
+        }
+        self.phase.set(Phase::Idle);
+
+        if self.unswept_old.borrow().is_empty() && self.unswept_young.borrow().is_empty() {
+            // Emptied tables would otherwise keep a word for every object they held
+            *self.unswept_old.borrow_mut() = vec![];
+            *self.unswept_young.borrow_mut() = vec![];
+            if self.flip_pending.replace(false) {
+                self.color.set(!self.color.get());
+            }
+            self.sweep_pending.set(false);
+            if !self.config.get().defer_finalizers {
+                self.run_finalizers();
+            }
+            self.pacer.collected(&self.config.get());
+            gc_debug!(self, "COLLECTED leaving {} bytes in the heap", self.pacer.heap_bytes());
+            true
+        } else {
+            false
+        }
+    }
+
+    // A collection cannot begin until the last one has been swept, because the
+    // survivors it left are still marked.
+    fn finish_sweep(self: Pin<&Self>) {
+        self.sweep_step(usize::MAX);
+    }
+
+    // Old survivors are not written to at all
+    unsafe fn sweep_old(self: Pin<&Self>, object: NonNull<Allocation<Data>>) {
+        if object.as_ref().is_marked(self.color.get()) {
+            self.old.borrow_mut().push(object);
+        } else {
+            self.free(object)
+        }
+    }
+
+    unsafe fn sweep_young_object(self: Pin<&Self>, object: NonNull<Allocation<Data>>) {
+        if object.as_ref().is_marked(self.color.get()) {
+            gc_trace!(self, "PROMOTING object at:        {:x}", object.as_ptr() as usize);
+            object.as_ref().promote();
+            // The survivors of a major collection are unmarked when the color
+            // flips, once the sweep is finished.
+            if !self.flip_pending.get() {
+                object.as_ref().unmark(self.color.get());
+            }
+            self.old.borrow_mut().push(object);
+        } else {
+            self.free(object)
+        }
+    }
+
+    // Objects with finalizers are queued, and the rest are freed at once
+    unsafe fn free(self: Pin<&Self>, object: NonNull<Allocation<Data>>) {
+        if object.as_ref().needs_finalize() {
+            self.finalize_queue.borrow_mut().push_back(object);
+        } else {
+            self.release(object);
+        }
+    }
+
+    unsafe fn release(self: Pin<&Self>, object: NonNull<Allocation<Data>>) {
+        gc_trace!(self, "FREEING unmarked object at: {:x}", object.as_ptr() as usize);
+        let object = object.as_ref();
+        if object.is_large() {
+            self.pacer.freed_large(object.size());
+        } else {
+            self.pacer.freed(object.size());
+        }
+        (object as *const Allocation<Data> as *mut Allocation<Data>).free();
+    }
+
+    /// Finalize and free every dead object which is waiting to be finalized,
+    /// then run every cleanup whose object has been collected, returning how
+    /// many finalizers and cleanups were run
+    ///
+    /// The sweep in progress is finished first. Objects are finalized in the
+    /// order they were found dead, each one being freed as soon as its finalizer
+    /// returns. Finalizers may allocate, root objects and collect; the objects
+    /// found dead meanwhile are finalized before this returns.
+    pub fn run_finalizers(self: Pin<&Self>) -> usize {
+        self.finish_sweep();
+        // Finalizers which are already being run are not run again
+        if self.finalizing.replace(true) {
+            return 0;
+        }
+        let mut finalized = 0;
+        loop {
+            let next = self.finalize_queue.borrow_mut().pop_front();
+            if let Some(object) = next {
+                unsafe { self.release(object) }
+                finalized += 1;
+                continue;
+            }
+            let cleanup = self.cleanup_queue.borrow_mut().pop_front();
+            match cleanup {
+                Some(cleanup)   => cleanup(),
+                None            => break,
+            }
+            finalized += 1;
+        }
+        self.finalizing.set(false);
+        finalized
+    }
+
+    pub fn count_pending_finalizers(&self) -> usize {
+        self.finalize_queue.borrow().len() + self.cleanup_queue.borrow().len()
+    }
+
+    /// Keep an object from being moved until the end of the next collection
+    pub fn hold(&self, object: NonNull<Allocation<Data>>) {
+        self.held.borrow_mut().push(object);
+    }
+
+    /// Run a function once an object has been collected
+    pub fn on_collect(&self, object: NonNull<Allocation<Data>>, cleanup: Box<dyn FnOnce()>) {
+        // The cleanup is not Send, but any of the threads sharing the heap may
+        // be the one to collect the object
+        if self.sync.is_some() {
+            panic!("a heap shared between threads cannot run cleanups");
+        }
+        self.cleanups.borrow_mut().push((object, cleanup));
+    }
+
+    /// Turn a white object gray
+    ///
+    /// Invariants: object must not be dangling and must be managed
+    pub unsafe fn shade(self: Pin<&Self>, object: NonNull<Allocation<Data>>) {
+        if self.phase.get() == Phase::MarkingYoung && object.as_ref().is_old() {
+            return;
+        }
+        if object.as_ref().mark(self.color.get()) {
+            gc_trace!(self, "MARKING object at:          {:x}", object.as_ptr() as usize);
+            // Leaves have nothing to trace
+            if !object.as_ref().is_leaf() {
+                self.gray.borrow_mut().push(object);
+            }
+        }
+    }
+
+    // Unmark an object joining the heap, or relocated within it. While the sweep
+    // of a major collection is pending, it must be unmarked once the color flips.
+    fn unmark(&self, object: &Allocation<Data>) {
+        object.unmark(self.color.get() != self.flip_pending.get())
+    }
+
+    pub unsafe fn write_barrier<T: Trace + ?Sized>(self: Pin<&Self>, ptr: GcPtr<T>) {
         if ptr.is_unmanaged() {
-            self.objects().insert(ptr.erased_pinned());
+            return;
+        }
+        let object = ptr.erased();
+        if self.phase.get() == Phase::Marking {
+            self.shade(object);
+        } else if !object.as_ref().is_old() {
+            // We do not know where the pointer is being written, so remember
+            // the young object itself rather than the location.
+            self.remembered.borrow_mut().insert(object);
         }
-        ptr.data().manage();
+    }
+
+    pub unsafe fn manage<T: Trace + ?Sized>(self: Pin<&Self>, ptr: GcPtr<T>) {
+        // Everything reachable from a managed object is already managed
+        if !ptr.is_unmanaged() {
+            if ptr.heap() != self.id {
+                self.unmanaged.borrow_mut().clear();
+                self.managing.set(false);
+                panic!("an object managed by one heap cannot be used in another");
+            }
+            return;
+        }
+
+        let object = ptr.erased_pinned();
+        object.set_heap(self.id);
+        self.unmark(&object);
+        if object.is_large() {
+            self.large.borrow_mut().push(ptr.erased());
+            self.pacer.allocated_large(object.size());
+        } else {
+            self.young.borrow_mut().push(ptr.erased());
+            self.young_bytes.set(self.young_bytes.get() + object.size());
+            self.pacer.allocated(object.size());
+        }
+        // Objects which join the heap during marking are allocated gray
+        if self.phase.get() == Phase::Marking {
+            self.shade(ptr.erased());
+        }
+        if !object.is_leaf() {
+            self.unmanaged.borrow_mut().push(ptr.erased());
+        }
+
+        // Managing an object's data calls back into this method for each of its
+        // unmanaged children; only the outermost call walks the worklist.
+        if !self.managing.replace(true) {
+            loop {
+                let next = self.unmanaged.borrow_mut().pop();
+                match next {
+                    Some(object)    => object.as_ref().manage_children(),
+                    None            => break,
+                }
+            }
+            self.managing.set(false);
+        }
+    }
+
+    /// Free every object in the heap, unless it is still rooted
+    ///
+    /// Returns false, leaving the heap untouched, if any root is still alive.
+    pub fn teardown(self: Pin<&Self>) -> bool {
+        if self.count_roots() != 0 {
+            return false;
+        }
+
+        drop(self.background.borrow_mut().take());
+        drop(self.workers.borrow_mut().take());
+        self.phase.set(Phase::Sweeping);
+        self.gray.borrow_mut().clear();
+        let objects = self.objects();
+        for table in &self.tables() {
+            table.borrow_mut().clear();
+        }
+        for object in objects {
+            unsafe { self.free(object) }
+        }
+        let cleanups = mem::replace(&mut *self.cleanups.borrow_mut(), vec![]);
+        self.cleanup_queue.borrow_mut().extend(cleanups.into_iter().map(|(_, cleanup)| cleanup));
+        self.run_finalizers();
+        true
+    }
+
+    /// Write to a traced location while no background marker is tracing
+    pub fn write<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        let marker = self.background.borrow().as_ref().map(|background| background.marker());
+        let _tracing = marker.as_ref().map(|marker| marker.lock());
+        f()
     }
 
     pub fn new_root(self: Pin<&Self>) -> usize {
+        // Roots are not dropped in stack order, since they may be on different
+        // threads or owned by HeapRoots, so the slots of dropped roots are
+        // reused in whatever order they are freed.
+        if let Some(idx) = self.free_roots.borrow_mut().pop() {
+            return idx;
+        }
         let mut roots = self.roots.borrow_mut();
-        let ret = roots.len();
         roots.push(None);
-        ret
+        roots.len() - 1
     }
 
     pub fn set_root<T: Trace + ?Sized>(self: Pin<&Self>, idx: usize, ptr: GcPtr<T>) {
         let root: NonNull<Allocation<Data>> = ptr.erased();
-        debug!("ENROOTING root at:          {:x} (idx {:x})", root.as_ptr() as usize, idx);
+        if unsafe { ptr.heap() } != self.id {
+            panic!("an object managed by one heap cannot be used in another");
+        }
+        gc_trace!(self, "ENROOTING root at:          {:x} (idx {:x})", root.as_ptr() as usize, idx);
         self.roots.borrow_mut()[idx] = Some(root);
+        if self.phase.get() == Phase::Marking {
+            unsafe {
+                self.shade(root);
+            }
+        }
     }
 
     pub fn pop_root(self: Pin<&Self>, idx: usize) {
-        debug_assert!(idx + 1 == self.roots.borrow().len());
-        if let Some(root) = self.roots.borrow_mut().pop().unwrap() {
-            debug!(" DROPPING root at:           {:x} (idx {:x})", root.as_ptr() as usize, idx);
+        if let Some(root) = self.roots.borrow_mut()[idx].take() {
+            gc_trace!(self, " DROPPING root at:           {:x} (idx {:x})", root.as_ptr() as usize, idx);
+        }
+        self.free_roots.borrow_mut().push(idx);
+    }
+
+    pub fn new_weak(&self, object: Option<NonNull<Allocation<Data>>>) -> usize {
+        if let Some(idx) = self.free_weaks.borrow_mut().pop() {
+            self.weaks.borrow_mut()[idx] = object;
+            return idx;
+        }
+        let mut weaks = self.weaks.borrow_mut();
+        weaks.push(object);
+        weaks.len() - 1
+    }
+
+    /// The object a weak reference points to, unless it has been cleared
+    pub fn weak(&self, idx: usize) -> Option<NonNull<Allocation<Data>>> {
+        self.weaks.borrow()[idx]
+    }
+
+    pub fn drop_weak(&self, idx: usize) {
+        self.weaks.borrow_mut()[idx] = None;
+        self.free_weaks.borrow_mut().push(idx);
+    }
+
+    pub fn new_soft(&self, object: Option<NonNull<Allocation<Data>>>) -> usize {
+        let soft = object.map(|object| Soft { object, idle: 0 });
+        if let Some(idx) = self.free_softs.borrow_mut().pop() {
+            self.softs.borrow_mut()[idx] = soft;
+            return idx;
+        }
+        let mut softs = self.softs.borrow_mut();
+        softs.push(soft);
+        softs.len() - 1
+    }
+
+    /// The object a soft reference points to, unless it has been cleared
+    ///
+    /// If `used` is true, the reference counts as having just been used.
+    pub fn soft(&self, idx: usize, used: bool) -> Option<NonNull<Allocation<Data>>> {
+        self.softs.borrow_mut()[idx].as_mut().map(|soft| {
+            if used {
+                soft.idle = 0;
+            }
+            soft.object
+        })
+    }
+
+    pub fn drop_soft(&self, idx: usize) {
+        self.softs.borrow_mut()[idx] = None;
+        self.free_softs.borrow_mut().push(idx);
+    }
+
+    pub fn new_ephemeron(&self, key: NonNull<Allocation<Data>>, value: NonNull<Allocation<Data>>) -> usize {
+        let ephemeron = Some((key, value));
+        if let Some(idx) = self.free_ephemerons.borrow_mut().pop() {
+            self.ephemerons.borrow_mut()[idx] = ephemeron;
+            return idx;
+        }
+        let mut ephemerons = self.ephemerons.borrow_mut();
+        ephemerons.push(ephemeron);
+        ephemerons.len() - 1
+    }
+
+    /// The key and value of an ephemeron, unless its key has been collected
+    pub fn ephemeron(&self, idx: usize) -> Option<(NonNull<Allocation<Data>>, NonNull<Allocation<Data>>)> {
+        self.ephemerons.borrow()[idx]
+    }
+
+    pub fn drop_ephemeron(&self, idx: usize) {
+        self.ephemerons.borrow_mut()[idx] = None;
+        self.free_ephemerons.borrow_mut().push(idx);
+    }
+
+    pub fn new_guardian(&self) -> usize {
+        if let Some(idx) = self.free_guardians.borrow_mut().pop() {
+            self.guardians.borrow_mut()[idx] = Some(Guarded::default());
+            return idx;
         }
+        let mut guardians = self.guardians.borrow_mut();
+        guardians.push(Some(Guarded::default()));
+        guardians.len() - 1
+    }
+
+    pub fn guard(&self, idx: usize, object: NonNull<Allocation<Data>>) {
+        self.guardians.borrow_mut()[idx].as_mut().unwrap().registered.push(object);
+    }
+
+    /// Take the next object a guardian has kept alive, which must be rooted
+    /// before the heap is next collected
+    pub fn poll_guardian(&self, idx: usize) -> Option<NonNull<Allocation<Data>>> {
+        self.guardians.borrow_mut()[idx].as_mut().unwrap().ready.pop_front()
+    }
+
+    /// Drop a guardian, leaving its objects to be collected as usual
+    pub fn drop_guardian(&self, idx: usize) {
+        self.guardians.borrow_mut()[idx] = None;
+        self.free_guardians.borrow_mut().push(idx);
+    }
+
+    pub fn sync(&self) -> Option<&SyncState> {
+        self.sync.as_ref()
+    }
+
+    /// Lock the heap, if it is shared between threads
+    pub fn lock(&self) -> Option<Guard<'_>> {
+        self.sync.as_ref().and_then(|sync| sync.lock())
+    }
+
+    // Stop every other thread using the heap until the result is dropped
+    fn stop_world(&self) -> Option<StoppedWorld<'_>> {
+        match &self.sync {
+            Some(sync) if sync.stop_world() => Some(StoppedWorld(sync)),
+            _                               => None,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn pacer(&self) -> &Pacer {
+        &self.pacer
+    }
+
+    pub fn config(&self) -> GcConfig {
+        self.config.get()
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.config.get().log_level
+    }
+
+    pub fn configure(&self, config: GcConfig) {
+        self.config.set(config);
+        self.pacer.reschedule(&config);
+    }
+
+    pub fn count_roots(&self) -> usize {
+        self.roots.borrow().len() - self.free_roots.borrow().len()
     }
 
     pub fn roots(&self) -> Ref<'_, [Option<NonNull<Allocation<Data>>>]> {
         Ref::map(self.roots.borrow(), |v| &v[..])
     }
 
-    pub fn objects<'a>(self: Pin<&'a Self>) -> Pin<&'a List<Allocation<Data>>> {
-        unsafe { Pin::map_unchecked(self, |this| &this.objects) }
+    /// Every object managed by the heap
+    pub fn objects(&self) -> Vec<NonNull<Allocation<Data>>> {
+        self.tables().iter().flat_map(|objects| objects.borrow().clone()).collect()
+    }
+
+    pub fn count_objects(&self) -> usize {
+        self.tables().iter().map(|objects| objects.borrow().len()).sum()
+    }
+
+    pub fn count_young_objects(&self) -> usize {
+        self.young.borrow().len()
+    }
+
+    fn tables(&self) -> [&RefCell<Vec<NonNull<Allocation<Data>>>>; 5] {
+        [&self.young, &self.old, &self.large, &self.unswept_young, &self.unswept_old]
+    }
+}
+
+struct StoppedWorld<'a>(&'a SyncState);
+
+impl<'a> Drop for StoppedWorld<'a> {
+    fn drop(&mut self) {
+        self.0.restart_world();
     }
 }
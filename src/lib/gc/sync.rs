@@ -0,0 +1,221 @@
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::pin::Pin;
+use std::ptr::NonNull;
+use std::sync::{Condvar, Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::heap::{self, Enter};
+use crate::state::GcState;
+
+/// A heap which can be shared between threads.
+///
+/// Each thread which uses the heap joins it as a mutator. Collections stop the
+/// world: the collecting thread waits until every other mutator is stopped,
+/// which happens whenever a mutator is waiting to use the heap, or is inside a
+/// call to `Mutator::blocking`.
+///
+/// Dropping the heap frees every object in it. If a root into the heap is
+/// still alive, the heap is leaked instead.
+pub struct SyncHeap {
+    state: ManuallyDrop<Pin<Box<GcState>>>,
+}
+
+// All access to the state goes through its lock.
+unsafe impl Send for SyncHeap { }
+unsafe impl Sync for SyncHeap { }
+
+impl SyncHeap {
+    pub fn new() -> SyncHeap {
+        SyncHeap { state: ManuallyDrop::new(Box::pin(GcState::new_sync(heap::next_id()))) }
+    }
+
+    /// Join the heap as a mutator for the duration of a function
+    ///
+    /// Panics if this thread has already joined the heap.
+    pub fn join<T, F: FnOnce(&Mutator<'_>) -> T>(&self, f: F) -> T {
+        if heap::is_registered(self.state().id()) {
+            panic!("a thread cannot join a heap which it has already joined");
+        }
+        self.sync().world.join();
+        heap::register(self.state());
+        let mutator = Mutator { heap: self, _not_sync: PhantomData };
+        f(&mutator)
+    }
+
+    fn state(&self) -> Pin<&GcState> {
+        self.state.as_ref()
+    }
+
+    fn sync(&self) -> &SyncState {
+        self.state.sync().unwrap()
+    }
+}
+
+impl Default for SyncHeap {
+    fn default() -> SyncHeap {
+        SyncHeap::new()
+    }
+}
+
+impl Drop for SyncHeap {
+    fn drop(&mut self) {
+        if self.state().teardown() {
+            unsafe { ManuallyDrop::drop(&mut self.state) }
+        }
+    }
+}
+
+/// A thread which has joined a `SyncHeap`.
+pub struct Mutator<'heap> {
+    heap: &'heap SyncHeap,
+    _not_sync: PhantomData<*const ()>,
+}
+
+impl<'heap> Mutator<'heap> {
+    /// Run a function with this as the current heap
+    pub fn enter<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        super::enter(Some(NonNull::from(&*self.heap.state())), f)
+    }
+
+    pub fn collect(&self) {
+        self.enter(super::collect)
+    }
+
+    /// Stop using the heap for the duration of a function, so that other
+    /// threads may collect it
+    ///
+    /// This must be used around anything which waits for another mutator, such
+    /// as joining its thread. The thread's default heap is current inside the
+    /// function.
+    pub fn blocking<T, F: FnOnce() -> T + Send>(&self, f: F) -> T {
+        let world = &self.heap.sync().world;
+        world.leave();
+        let _rejoin = Rejoin(world);
+        super::enter(None, f)
+    }
+}
+
+impl<'heap> Enter for Mutator<'heap> {
+    fn enter<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        Mutator::enter(self, f)
+    }
+}
+
+impl<'heap> Drop for Mutator<'heap> {
+    fn drop(&mut self) {
+        heap::unregister(self.heap.state());
+        self.heap.sync().world.leave();
+    }
+}
+
+struct Rejoin<'a>(&'a World);
+
+impl<'a> Drop for Rejoin<'a> {
+    fn drop(&mut self) {
+        self.0.join();
+    }
+}
+
+/// The lock and the mutators of a heap shared between threads
+#[derive(Default)]
+pub struct SyncState {
+    world: World,
+    lock: Mutex<()>,
+    // The thread holding the lock, so that it can be reacquired
+    owner: AtomicUsize,
+}
+
+pub struct Guard<'a> {
+    sync: &'a SyncState,
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl SyncState {
+    /// Lock the heap, unless this thread already holds the lock
+    pub fn lock(&self) -> Option<Guard<'_>> {
+        let thread = thread_id();
+        if self.owner.load(Ordering::Relaxed) == thread {
+            return None;
+        }
+
+        let guard = match self.lock.try_lock() {
+            Ok(guard)                       => guard,
+            Err(TryLockError::WouldBlock)   => {
+                // Waiting for the lock counts as being stopped
+                self.world.leave();
+                let _rejoin = Rejoin(&self.world);
+                self.lock.lock().unwrap()
+            }
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+        };
+        self.owner.store(thread, Ordering::Relaxed);
+        Some(Guard { sync: self, _guard: guard })
+    }
+
+    /// Wait until every other mutator has stopped, returning false if the
+    /// world was already stopped
+    pub fn stop_world(&self) -> bool {
+        self.world.stop()
+    }
+
+    pub fn restart_world(&self) {
+        self.world.restart()
+    }
+}
+
+impl<'a> Drop for Guard<'a> {
+    fn drop(&mut self) {
+        self.sync.owner.store(0, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct World {
+    state: Mutex<WorldState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct WorldState {
+    running: usize,
+    stopped: bool,
+}
+
+impl World {
+    fn join(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.stopped {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.running += 1;
+    }
+
+    fn leave(&self) {
+        self.state.lock().unwrap().running -= 1;
+        self.changed.notify_all();
+    }
+
+    // Only called by a running mutator holding the heap's lock
+    fn stop(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            return false;
+        }
+        state.stopped = true;
+        while state.running > 1 {
+            state = self.changed.wait(state).unwrap();
+        }
+        true
+    }
+
+    fn restart(&self) {
+        self.state.lock().unwrap().stopped = false;
+        self.changed.notify_all();
+    }
+}
+
+fn thread_id() -> usize {
+    thread_local!(static THREAD: u8 = 0);
+    THREAD.with(|thread| thread as *const u8 as usize)
+}
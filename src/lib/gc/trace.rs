@@ -2,6 +2,14 @@ pub unsafe trait Trace {
     unsafe fn mark(&self);
     unsafe fn manage(&self);
     unsafe fn finalize(&mut self);
+    unsafe fn relocate(&mut self);
+
+    /// Tell if values of this type never contain a managed pointer, and are
+    /// finalized by dropping them. The collector never traces such an object,
+    /// and does not finalize it at all if it has no drop glue.
+    fn is_leaf() -> bool where Self: Sized {
+        false
+    }
 }
 
 pub unsafe trait NullTrace: Trace { }
@@ -17,6 +25,12 @@ unsafe impl<T: Trace> Trace for Option<T> {
     unsafe fn finalize(&mut self) {
         if let Some(inner) = self { inner.finalize() }
     }
+    unsafe fn relocate(&mut self) {
+        if let Some(inner) = self { inner.relocate() }
+    }
+    fn is_leaf() -> bool {
+        T::is_leaf()
+    }
 }
 
 unsafe impl<T: NullTrace> NullTrace for Option<T> { }
@@ -40,6 +54,15 @@ unsafe impl<T: Trace, E: Trace> Trace for Result<T, E> {
             Err(error)  => error.finalize(),
         }
     }
+    unsafe fn relocate(&mut self) {
+        match self {
+            Ok(inner)   => inner.relocate(),
+            Err(error)  => error.relocate(),
+        }
+    }
+    fn is_leaf() -> bool {
+        T::is_leaf() && E::is_leaf()
+    }
 }
 
 unsafe impl<T: NullTrace, E: NullTrace> NullTrace for Result<T, E> { }
@@ -54,6 +77,9 @@ unsafe impl<T: Trace> Trace for [T] {
     unsafe fn finalize(&mut self) {
         for elem in self { elem.finalize() }
     }
+    unsafe fn relocate(&mut self) {
+        for elem in self { elem.relocate() }
+    }
 }
 
 unsafe impl<T: NullTrace> NullTrace for [T] { }
@@ -66,24 +92,46 @@ macro_rules!
             unsafe fn finalize(&mut self) {
                 ptr::drop_in_place(self as *mut Self)
             }
+            unsafe fn relocate(&mut self) { }
+            fn is_leaf() -> bool { true }
         }
         unsafe impl NullTrace for $t { }
     )*}
 }
 
+// Unsized types are never allocated on their own, so they need not be leaves
+macro_rules!
+    trace_unsized { ($($t:ty)*) => {$(
+        unsafe impl Trace for $t {
+            unsafe fn mark(&self) { }
+            unsafe fn manage(&self) { }
+            unsafe fn finalize(&mut self) {
+                ptr::drop_in_place(self as *mut Self)
+            }
+            unsafe fn relocate(&mut self) { }
+        }
+        unsafe impl NullTrace for $t { }
+    )*}
+}
+
+trace_unsized!(
+    str
+    std::io::BufRead
+    std::io::Read
+    std::io::Write
+    std::path::Path
+);
+
 trace_simple!(
     i8  i16 i32 i64 isize
     u8  u16 u32 u64 usize
     f32     f64
     char    bool
-    str     String
+    String
     std::fs::File
     std::fs::FileType
     std::fs::Metadata
     std::fs::OpenOptions
-    std::io::BufRead
-    std::io::Read
-    std::io::Write
     std::io::Stdin
     std::io::Stdout
     std::io::Stderr
@@ -95,7 +143,6 @@ trace_simple!(
     std::net::Ipv6Addr
     std::net::SocketAddrV4
     std::net::SocketAddrV6
-    std::path::Path
     std::path::PathBuf
     std::process::Command
     std::process::Child
@@ -122,6 +169,12 @@ macro_rules! trace_arrays {
             unsafe fn finalize(&mut self) {
                 <_ as AsMut<[T]>>::as_mut(self).finalize()
             }
+            unsafe fn relocate(&mut self) {
+                <_ as AsMut<[T]>>::as_mut(self).relocate()
+            }
+            fn is_leaf() -> bool {
+                T::is_leaf()
+            }
         }
         unsafe impl<T: NullTrace> NullTrace for [T; $N] { }
     )*};
@@ -146,6 +199,12 @@ macro_rules! trace_tuples {
             unsafe fn finalize(&mut self) {
                 $(self.$N.finalize();)*
             }
+            unsafe fn relocate(&mut self) {
+                $(self.$N.relocate();)*
+            }
+            fn is_leaf() -> bool {
+                true $(&& $T::is_leaf())*
+            }
         }
         unsafe impl<$($T: NullTrace,)*> NullTrace for ($($T,)*) { }
     )*};
@@ -185,6 +244,14 @@ unsafe impl<T: Trace> Trace for Vec<T> {
         let this = mem::transmute::<&mut Vec<T>, &mut Vec<ManuallyDrop<T>>>(self);
         ptr::drop_in_place(this as *mut Vec<ManuallyDrop<T>>);
     }
+
+    unsafe fn relocate(&mut self) {
+        for elem in self { elem.relocate(); }
+    }
+
+    fn is_leaf() -> bool {
+        T::is_leaf()
+    }
 }
 
 unsafe impl<T: NullTrace> NullTrace for Vec<T> { }
@@ -203,6 +270,14 @@ unsafe impl<T: Trace> Trace for VecDeque<T> {
         let this = mem::transmute::<&mut VecDeque<T>, &mut VecDeque<ManuallyDrop<T>>>(self);
         ptr::drop_in_place(this as *mut VecDeque<ManuallyDrop<T>>);
     }
+
+    unsafe fn relocate(&mut self) {
+        for elem in self { elem.relocate(); }
+    }
+
+    fn is_leaf() -> bool {
+        T::is_leaf()
+    }
 }
 
 unsafe impl<T: NullTrace> NullTrace for VecDeque<T> { }
@@ -221,6 +296,14 @@ unsafe impl<T: Trace> Trace for LinkedList<T> {
         let this = mem::transmute::<&mut LinkedList<T>, &mut LinkedList<ManuallyDrop<T>>>(self);
         ptr::drop_in_place(this as *mut LinkedList<ManuallyDrop<T>>);
     }
+
+    unsafe fn relocate(&mut self) {
+        for elem in self { elem.relocate(); }
+    }
+
+    fn is_leaf() -> bool {
+        T::is_leaf()
+    }
 }
 
 unsafe impl<T: NullTrace> NullTrace for LinkedList<T> { }
@@ -239,6 +322,17 @@ unsafe impl<T: Trace + Ord> Trace for BinaryHeap<T> {
         let iter = mem::transmute::<binary_heap::IntoIter<T>, binary_heap::IntoIter<ManuallyDrop<T>>>(iter);
         iter.for_each(|mut elem| elem.finalize());
     }
+
+    // A binary heap gives no mutable access to its elements, so it is drained
+    // and rebuilt from the relocated elements.
+    unsafe fn relocate(&mut self) {
+        let elems: Vec<T> = self.drain().collect();
+        self.extend(elems.into_iter().map(|mut elem| { elem.relocate(); elem }));
+    }
+
+    fn is_leaf() -> bool {
+        T::is_leaf()
+    }
 }
 
 unsafe impl<T: NullTrace + Ord> NullTrace for BinaryHeap<T> { }
@@ -260,6 +354,15 @@ unsafe impl<T, S> Trace for HashSet<T, S> where
         let iter = mem::transmute::<hash_set::IntoIter<T>, hash_set::IntoIter<ManuallyDrop<T>>>(iter);
         iter.for_each(|mut elem| elem.finalize());
     }
+
+    unsafe fn relocate(&mut self) {
+        let elems: Vec<T> = self.drain().collect();
+        self.extend(elems.into_iter().map(|mut elem| { elem.relocate(); elem }));
+    }
+
+    fn is_leaf() -> bool {
+        T::is_leaf()
+    }
 }
 
 unsafe impl<T, S> NullTrace for HashSet<T, S> where
@@ -294,6 +397,19 @@ unsafe impl<K, V, S> Trace for HashMap<K, V, S> where
             value.finalize();
         });
     }
+
+    unsafe fn relocate(&mut self) {
+        let entries: Vec<(K, V)> = self.drain().collect();
+        self.extend(entries.into_iter().map(|(mut key, mut value)| {
+            key.relocate();
+            value.relocate();
+            (key, value)
+        }));
+    }
+
+    fn is_leaf() -> bool {
+        K::is_leaf() && V::is_leaf()
+    }
 }
 
 unsafe impl<K, V, S> NullTrace for HashMap<K, V, S> where
@@ -318,6 +434,15 @@ unsafe impl<T> Trace for BTreeSet<T> where
         let iter = mem::transmute::<btree_set::IntoIter<T>, btree_set::IntoIter<ManuallyDrop<T>>>(iter);
         iter.for_each(|mut elem| elem.finalize());
     }
+
+    unsafe fn relocate(&mut self) {
+        let elems = mem::replace(self, BTreeSet::new());
+        self.extend(elems.into_iter().map(|mut elem| { elem.relocate(); elem }));
+    }
+
+    fn is_leaf() -> bool {
+        T::is_leaf()
+    }
 }
 
 unsafe impl<T> NullTrace for BTreeSet<T> where
@@ -350,6 +475,19 @@ unsafe impl<K, V> Trace for BTreeMap<K, V> where
             value.finalize();
         });
     }
+
+    unsafe fn relocate(&mut self) {
+        let entries = mem::replace(self, BTreeMap::new());
+        self.extend(entries.into_iter().map(|(mut key, mut value)| {
+            key.relocate();
+            value.relocate();
+            (key, value)
+        }));
+    }
+
+    fn is_leaf() -> bool {
+        K::is_leaf() && V::is_leaf()
+    }
 }
 
 unsafe impl<K, V> NullTrace for BTreeMap<K, V> where
@@ -366,6 +504,8 @@ unsafe impl<T: NullTrace> Trace for Cell<T> {
     unsafe fn finalize(&mut self) {
         ptr::drop_in_place(self as *mut Self)
     }
+    unsafe fn relocate(&mut self) { }
+    fn is_leaf() -> bool { true }
 }
 
 unsafe impl<T: NullTrace> NullTrace for Cell<T> { }
@@ -376,13 +516,21 @@ unsafe impl<T: NullTrace> Trace for RefCell<T> {
     unsafe fn finalize(&mut self) {
         ptr::drop_in_place(self as *mut Self)
     }
+    unsafe fn relocate(&mut self) { }
+    fn is_leaf() -> bool { true }
 }
 
 unsafe impl<T: NullTrace> NullTrace for RefCell<T> { }
 
 unsafe impl<T: Trace> Trace for PinCell<T> {
+    // The contents of the cell can be changed through a borrow without a write
+    // barrier, so a background marker leaves them until the mutator has
+    // stopped. Marking does not touch the borrow flag, so that the cell can be
+    // traced while the mutator holds a borrow of it.
     unsafe fn mark(&self) {
-        self.borrow().mark()
+        if !T::is_leaf() && !crate::mark::defer() {
+            (*self.as_ptr()).mark()
+        }
     }
     unsafe fn manage(&self) {
         self.borrow().manage()
@@ -390,6 +538,12 @@ unsafe impl<T: Trace> Trace for PinCell<T> {
     unsafe fn finalize(&mut self) {
         self.get_mut().finalize()
     }
+    unsafe fn relocate(&mut self) {
+        self.get_mut().relocate()
+    }
+    fn is_leaf() -> bool {
+        T::is_leaf()
+    }
 }
 
 unsafe impl<T: NullTrace> NullTrace for PinCell<T> { }
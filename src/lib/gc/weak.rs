@@ -0,0 +1,44 @@
+use crate::gc_ptr::GcPtr;
+use crate::heap;
+
+/// A reference to a managed object which does not keep it alive
+///
+/// The reference is cleared by the collection which finds the object dead,
+/// before the object is finalized.
+pub struct Weak<T: ?Sized> {
+    idx: usize,
+    heap: u32,
+    // Only the address of the object is kept by the heap
+    ptr: GcPtr<T>,
+}
+
+impl<T: ?Sized> Weak<T> {
+    /// Invariants: ptr must not be dangling and must be managed
+    pub unsafe fn new(ptr: GcPtr<T>) -> Weak<T> {
+        let heap = ptr.heap();
+        let idx = heap::with_heap(heap, |gc| gc.new_weak(Some(ptr.erased())));
+        Weak { idx, heap, ptr }
+    }
+
+    /// The object this refers to, unless it has been collected
+    ///
+    /// The object must be rooted before the heap is next collected.
+    pub fn get(&self) -> Option<GcPtr<T>> {
+        let object = heap::try_with_heap(self.heap, |gc| gc.weak(self.idx));
+        object.and_then(|object| object).map(|object| self.ptr.with_address(object))
+    }
+}
+
+impl<T: ?Sized> Clone for Weak<T> {
+    fn clone(&self) -> Weak<T> {
+        let idx = heap::with_heap(self.heap, |gc| gc.new_weak(gc.weak(self.idx)));
+        Weak { idx, ..*self }
+    }
+}
+
+impl<T: ?Sized> Drop for Weak<T> {
+    fn drop(&mut self) {
+        // The heap may already have been dropped, along with its weak references
+        heap::try_with_heap(self.heap, |gc| gc.drop_weak(self.idx));
+    }
+}
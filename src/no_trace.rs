@@ -44,4 +44,5 @@ unsafe impl<T: ?Sized> Trace for NoTrace<T> {
     unsafe fn mark(&self) { }
     unsafe fn manage(&self) { }
     unsafe fn finalize(&mut self) { }
+    unsafe fn relocate(&mut self) { }
 }
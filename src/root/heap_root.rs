@@ -1,14 +1,21 @@
+use std::cell::Cell;
 use std::ops::Deref;
-use std::pin::Pin;
+use std::ptr;
 
-use gc::{GcPtr, Root, Trace};
+use gc::{GcPtr, Trace};
 
 use crate::Gc;
 use crate::root::Reroot;
 
+/// A root which owns its slot in the heap, rather than borrowing it from the
+/// stack
+///
+/// HeapRoots can be stored anywhere, cloned, and dropped in any order. A
+/// HeapRoot which is stored inside an object managed by the GC gives up its
+/// slot, and becomes an ordinary traced pointer, so that it cannot keep alive
+/// the object containing it.
 pub struct HeapRoot<T: ?Sized> {
-    #[allow(dead_code)]
-    root: Pin<Box<Root>>,
+    root: Cell<Option<gc::Root>>,
     ptr: GcPtr<T>,
 }
 
@@ -17,8 +24,10 @@ impl<'root, T> HeapRoot<T> where
     T::Rerooted: Trace,
 {
     pub fn new(data: T) -> HeapRoot<T::Rerooted> {
+        let root = gc::Root::new();
+        root.enter(gc::safepoint);
         unsafe {
-            HeapRoot::make(gc::alloc(data))
+            HeapRoot::make(root, gc::alloc_unmanaged(data))
         }
     }
 }
@@ -29,40 +38,55 @@ impl<'root, T> HeapRoot<T> where
 {
     pub fn reroot(gc: Gc<'_, T>) -> HeapRoot<T::Rerooted> {
         unsafe {
-            HeapRoot::make(Gc::raw(gc))
+            HeapRoot::make(gc::Root::new_for(Gc::raw(gc)), Gc::raw(gc))
         }
     }
-}
 
-impl<'root, T> HeapRoot<T> where
-    T: Reroot<'root> + ?Sized,
-    T::Rerooted: Trace,
-{
-    unsafe fn make(ptr: GcPtr<T>) -> HeapRoot<T::Rerooted> {
-        let ptr = super::reroot(ptr);
-        let root = Pin::from(Box::new(Root::new(ptr)));
-        gc::enroot(root.as_ref());
-        HeapRoot { root, ptr }
+    unsafe fn make(root: gc::Root, ptr: GcPtr<T>) -> HeapRoot<T::Rerooted> {
+        // The object must be rooted before another thread can collect the heap
+        let ptr = root.enter(|| {
+            let ptr = super::reroot(ptr);
+            root.enroot(ptr);
+            ptr
+        });
+        HeapRoot { root: Cell::new(Some(root)), ptr }
     }
 }
 
 impl<T: ?Sized> HeapRoot<T> {
     pub fn gc<'root>(&'root self) -> Gc<'root, T> {
         unsafe {
+            self.hold();
             Gc::rooted(self.ptr)
         }
     }
+
+    /// Tell if this HeapRoot still roots its object, rather than being traced
+    /// from the managed object which contains it
+    pub fn is_rooted(&self) -> bool {
+        let root = self.root.take();
+        let rooted = root.is_some();
+        self.root.set(root);
+        rooted
+    }
+
+    // A traced HeapRoot does not keep its object from being relocated, so it
+    // is held like any other object read through a Gc
+    unsafe fn hold(&self) {
+        if !self.is_rooted() {
+            self.ptr.hold();
+        }
+    }
 }
 
 impl<T: Trace + ?Sized> Clone for HeapRoot<T> {
     fn clone(&self) -> HeapRoot<T> {
-        let root = unsafe {
-            Pin::from(Box::new(Root::new(self.ptr)))
-        };
-        gc::enroot(root.as_ref());
-        HeapRoot {
-            root,
-            ptr: self.ptr,
+        // The clone is rooted in the heap which manages the object, even if the
+        // original is traced instead of rooted
+        unsafe {
+            let root = gc::Root::new_for(self.ptr);
+            root.enroot(self.ptr);
+            HeapRoot { root: Cell::new(Some(root)), ptr: self.ptr }
         }
     }
 }
@@ -71,7 +95,33 @@ impl<T: ?Sized> Deref for HeapRoot<T> {
     type Target = T;
     fn deref(&self) -> &T {
         unsafe {
+            self.hold();
             self.ptr.data()
         }
     }
 }
+
+unsafe impl<'root, T: Reroot<'root> + ?Sized> Reroot<'root> for HeapRoot<T> {
+    type Rerooted = HeapRoot<T::Rerooted>;
+}
+
+// Once the object containing it is managed, its root is dropped and the
+// pointer is traced like a GcStore.
+unsafe impl<T: Trace + ?Sized> Trace for HeapRoot<T> {
+    unsafe fn mark(&self) {
+        self.ptr.mark();
+    }
+
+    unsafe fn manage(&self) {
+        self.ptr.manage();
+        drop(self.root.take());
+    }
+
+    unsafe fn finalize(&mut self) {
+        ptr::drop_in_place(self as *mut Self)
+    }
+
+    unsafe fn relocate(&mut self) {
+        self.ptr.relocate();
+    }
+}
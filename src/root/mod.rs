@@ -1,5 +1,7 @@
+mod heap_root;
 mod reroot;
 mod stack_root;
 
+pub use self::heap_root::*;
 pub use self::reroot::*;
 pub use self::stack_root::*;
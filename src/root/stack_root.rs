@@ -19,8 +19,12 @@ impl<'root> Root<'root> {
         T: Reroot<'root> + Trace,
         T::Rerooted: Trace,
     {
+        let ptr = self.root.enter(|| {
+            gc::safepoint();
+            gc::alloc_unmanaged(data)
+        });
         unsafe {
-            self.make(gc::alloc_unmanaged(data))
+            self.make(ptr)
         }
     }
 
@@ -33,22 +37,52 @@ impl<'root> Root<'root> {
         }
     }
 
-    pub(crate) unsafe fn make<T>(mut self, ptr: GcPtr<T>) -> Gc<'root, T::Rerooted> where
+    pub(crate) unsafe fn make<T>(self, ptr: GcPtr<T>) -> Gc<'root, T::Rerooted> where
         T: Reroot<'root> + ?Sized,
         T::Rerooted: Trace,
     {
-        let ptr = super::reroot(ptr);
-        self.emplace(ptr);
+        // The object must be rooted before another thread can collect the heap
+        let ptr = self.root.enter(|| {
+            let ptr = super::reroot(ptr);
+            self.emplace(ptr);
+            ptr
+        });
         Gc::rooted(ptr)
     }
 
-    unsafe fn emplace<T: Trace + ?Sized>(&mut self, ptr: GcPtr<T>) {
-        Pin::get_mut(Pin::as_mut(&mut self.root)).enroot(ptr)
+    // Root an object which is only kept alive by the heap until it is rooted,
+    // such as one behind a weak reference
+    pub(crate) fn make_from<T, F>(self, get: F) -> Option<Gc<'root, T::Rerooted>> where
+        T: Reroot<'root> + ?Sized,
+        T::Rerooted: Trace,
+        F: FnOnce() -> Option<GcPtr<T>>,
+    {
+        // The object must be rooted before another thread can collect it
+        let ptr = self.root.enter(|| unsafe {
+            get().map(|ptr| {
+                let ptr = super::reroot(ptr);
+                self.emplace(ptr);
+                ptr
+            })
+        });
+        ptr.map(|ptr| unsafe { Gc::rooted(ptr) })
+    }
+
+    unsafe fn emplace<T: Trace + ?Sized>(&self, ptr: GcPtr<T>) {
+        self.root.enroot(ptr)
     }
 }
 
 #[macro_export]
 macro_rules! letroot {
+    ($root:ident in $heap:expr) => {
+        let mut $root = $crate::raw::Root::new_in(&$heap);
+
+        #[allow(unused_mut)]
+        let mut $root = unsafe {
+            $crate::Root::new(&mut $root)
+        };
+    };
     ($($root:ident)*) => {$(
         // Ensure the root is owned
         let mut $root = $crate::raw::Root::new();
@@ -1,4 +1,4 @@
-use crate::{Gc, GcStore};
+use crate::{Gc, GcPinCell, GcStore};
 
 pub unsafe trait Store<'root> {
     type Accessor: 'root;
@@ -8,16 +8,23 @@ pub unsafe trait Store<'root> {
 unsafe impl<'root, 'r, T: ?Sized + 'root> Store<'root> for GcStore<'r, T> {
     type Accessor = Gc<'root, T>;
     unsafe fn rooted(this: &'root Self) -> Self::Accessor {
-        Gc::rooted(GcStore::raw(this))
+        let ptr = GcStore::raw(this);
+        // `compact` can't find the Gc pointers derived from a GcStore, so the
+        // objects behind them are held in place until the next collection.
+        ptr.hold();
+        Gc::rooted(ptr)
     }
 }
 
 macro_rules! transmute_store {
-    ($(for<$($T:ident),*> $from:ty => $to:ty;)*) => {$(
+    ($(for<$($T:ident),*> $from:ty => $to:ty, |$this:ident| $stores:expr;)*) => {$(
         unsafe impl<'root, 'r, $($T: ?Sized + 'root,)*> Store<'root> for $from {
             type Accessor = &'root $to;
-            unsafe fn rooted(this: &'root $from) -> &'root $to {
-                std::mem::transmute::<&'root $from, &'root $to>(this)
+            unsafe fn rooted($this: &'root $from) -> &'root $to {
+                for store in $stores {
+                    GcStore::raw(store).hold();
+                }
+                std::mem::transmute::<&'root $from, &'root $to>($this)
             }
         }
     )*}
@@ -28,13 +35,14 @@ use std::collections::*;
 use pin_cell::PinCell;
 
 transmute_store! {
-    for<T> Box<GcStore<'r, T>> => Box<Gc<'root, T>>;
-    for<T> Option<GcStore<'r, T>> => Option<Gc<'root, T>>;
-    for<T> [GcStore<'r, T>] => [Gc<'root, T>];
-    for<T> Vec<GcStore<'r, T>> => Vec<Gc<'root, T>>;
-    for<T> VecDeque<GcStore<'r, T>> => VecDeque<Gc<'root, T>>;
-    for<T> HashSet<GcStore<'r, T>> => HashSet<Gc<'root, T>>;
-    for<T> BTreeSet<GcStore<'r, T>> => BTreeSet<Gc<'root, T>>;
-    for<T> BinaryHeap<GcStore<'r, T>> => BinaryHeap<Gc<'root, T>>;
-    for<T> PinCell<GcStore<'r, T>> => PinCell<Gc<'root, T>>;
+    for<T> Box<GcStore<'r, T>> => Box<Gc<'root, T>>, |this| Some(&**this);
+    for<T> Option<GcStore<'r, T>> => Option<Gc<'root, T>>, |this| this;
+    for<T> [GcStore<'r, T>] => [Gc<'root, T>], |this| this;
+    for<T> Vec<GcStore<'r, T>> => Vec<Gc<'root, T>>, |this| this;
+    for<T> VecDeque<GcStore<'r, T>> => VecDeque<Gc<'root, T>>, |this| this;
+    for<T> HashSet<GcStore<'r, T>> => HashSet<Gc<'root, T>>, |this| this;
+    for<T> BTreeSet<GcStore<'r, T>> => BTreeSet<Gc<'root, T>>, |this| this;
+    for<T> BinaryHeap<GcStore<'r, T>> => BinaryHeap<Gc<'root, T>>, |this| this;
+    // A GcPinCell holds the object in it in place whenever a Gc is read out of it
+    for<T> PinCell<GcStore<'r, T>> => GcPinCell<'root, T>, |this| None::<&GcStore<'r, T>>;
 }
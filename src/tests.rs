@@ -1,5 +1,7 @@
 use super::*;
 
+use pin_cell::{PinCell, PinMut};
+
 
 #[test]
 fn stack_rooted() {
@@ -50,3 +52,1045 @@ fn rerooting() {
     collect();
     assert_eq!(raw::count_managed_objects(), 0);
 }
+
+#[test]
+fn incremental_write_barrier() {
+    let _ = env_logger::try_init();
+    letroot!(root_a root_b);
+
+    let a = root_a.gc(PinCell::new(GcStore::new(10)));
+    let b = root_b.gc(PinCell::new(GcStore::new(20)));
+    let cell_a: &GcPinCell<i32> = unsafe { raw::Store::rooted(&*a) };
+    let cell_b: &GcPinCell<i32> = unsafe { raw::Store::rooted(&*b) };
+
+    // Begin marking; this traces `b`, the last root to be shaded, but not `a`
+    assert!(!collect_step(1));
+
+    // Swap the contents, moving the object in `a` behind the already traced `b`
+    let x = cell_a.get();
+    let y = cell_b.get();
+    cell_b.set(x);
+    cell_a.set(y);
+
+    while !collect_step(1) { }
+
+    // Nothing was lost, because the write barrier shaded both objects
+    assert_eq!(raw::count_managed_objects(), 4);
+    assert_eq!(*cell_a.get(), 20);
+    assert_eq!(*cell_b.get(), 10);
+}
+
+#[test]
+fn generational() {
+    let _ = env_logger::try_init();
+    letroot!(root);
+
+    let a = root.gc(PinCell::new(GcStore::new(0)));
+    let cell: &GcPinCell<i32> = unsafe { raw::Store::rooted(&*a) };
+
+    // Unreachable young objects are freed, survivors are promoted
+    {   letroot!(temp);
+        temp.gc(1);
+    }
+    collect_minor();
+    assert_eq!(raw::count_managed_objects(), 2);
+    assert_eq!(raw::count_young_objects(), 0);
+
+    // Unreachable old objects are not freed by a minor collection
+    {   letroot!(temp);
+        temp.gc(2);
+        collect_minor();
+    }
+    collect_minor();
+    assert_eq!(raw::count_managed_objects(), 3);
+
+    // A young object only reachable from an old one is kept alive by the
+    // remembered set
+    {   letroot!(temp);
+        cell.set(temp.gc(3));
+    }
+    collect_minor();
+    assert_eq!(raw::count_managed_objects(), 4);
+    assert_eq!(*cell.get(), 3);
+
+    // A major collection frees the old garbage
+    collect();
+    assert_eq!(raw::count_managed_objects(), 2);
+    assert_eq!(*cell.get(), 3);
+}
+
+#[test]
+fn compaction() {
+    let _ = env_logger::try_init();
+    letroot!(root);
+
+    fn addr(ptr: raw::GcPtr<i32>) -> usize {
+        unsafe { ptr.data() as *const i32 as usize }
+    }
+
+    let triple = root.gc((GcStore::new(1), GcStore::new(2), PinCell::new(GcStore::new(3))));
+    let triple_addr = &*triple as *const _ as usize;
+    let movable_addr = addr(GcStore::raw(&triple.0));
+
+    // A Gc derived from a field is held across the compaction
+    let derived: Gc<i32> = unsafe { raw::Store::rooted(&triple.1) };
+    let cell: &GcPinCell<i32> = unsafe { raw::Store::rooted(&triple.2) };
+    let read = cell.get();
+
+    unsafe { raw::compact(); }
+
+    // Only the object which is neither rooted nor read through a Gc has moved
+    assert_eq!(&*triple as *const _ as usize, triple_addr);
+    assert_ne!(addr(GcStore::raw(&triple.0)), movable_addr);
+    assert_eq!(addr(GcStore::raw(&triple.1)), addr(Gc::raw(derived)));
+    assert_eq!(addr(GcStore::raw(&*triple.2.borrow())), addr(Gc::raw(read)));
+
+    assert_eq!(raw::count_managed_objects(), 4);
+    assert_eq!(unsafe { *GcStore::raw(&triple.0).data() }, 1);
+    assert_eq!(*derived, 2);
+    assert_eq!(*read, 3);
+
+    // Objects are only held until the end of the next collection, so those
+    // read through the field and the cell are moved by a later compaction
+    let (held, in_cell) = (addr(GcStore::raw(&triple.1)), addr(GcStore::raw(&*triple.2.borrow())));
+    unsafe { raw::compact(); }
+    assert_ne!(addr(GcStore::raw(&triple.1)), held);
+    assert_ne!(addr(GcStore::raw(&*triple.2.borrow())), in_cell);
+    assert_eq!(*cell.get(), 3);
+}
+
+#[test]
+fn deep_graph() {
+    let _ = env_logger::try_init();
+
+    struct Node<'root> {
+        next: Option<GcStore<'root, Node<'root>>>,
+    }
+
+    unsafe impl<'root> raw::Trace for Node<'root> {
+        unsafe fn mark(&self) { self.next.mark() }
+        unsafe fn manage(&self) { self.next.manage() }
+        unsafe fn finalize(&mut self) { self.next.finalize() }
+        unsafe fn relocate(&mut self) { self.next.relocate() }
+    }
+
+    unsafe impl<'root, 'r> raw::Reroot<'root> for Node<'r> {
+        type Rerooted = Node<'root>;
+    }
+
+    // Far too deep to manage or mark recursively
+    let mut next = None;
+    for _ in 0..100_000 {
+        next = Some(GcStore::new(Node { next }));
+    }
+
+    {   letroot!(root);
+        root.gc(Node { next });
+        assert_eq!(raw::count_managed_objects(), 100_001);
+        collect();
+        assert_eq!(raw::count_managed_objects(), 100_001);
+    }
+
+    collect();
+    assert_eq!(raw::count_managed_objects(), 0);
+}
+
+#[test]
+fn automatic_collection() {
+    let _ = env_logger::try_init();
+    GcConfig::new().initial_threshold(1024).install();
+
+    for i in 0..1000 {
+        letroot!(root);
+        root.gc(i);
+    }
+
+    // Garbage was collected as it was allocated, without an explicit collect
+    assert!(raw::count_managed_objects() < 100);
+    assert!(raw::heap_size() < 2048);
+}
+
+#[test]
+fn stress_and_max_heap_size() {
+    let _ = env_logger::try_init();
+    GcConfig::new().stress(true).install();
+
+    letroot!(root);
+    let kept = root.gc(0);
+    for i in 0..10 {
+        letroot!(temp);
+        temp.gc(i);
+        assert_eq!(raw::count_managed_objects(), 2);
+    }
+    assert_eq!(*kept, 0);
+
+    // Only `kept` fits in the heap; a second live object does not
+    collect();
+    GcConfig::new().max_heap_size(raw::heap_size()).install();
+    let result = std::panic::catch_unwind(|| {
+        letroot!(temp);
+        temp.gc(1);
+        letroot!(temp);
+        temp.gc(2);
+    });
+    assert!(result.is_err());
+}
+
+#[test]
+fn max_heap_size_with_dead_objects() {
+    let _ = env_logger::try_init();
+    letroot!(root);
+    let kept = root.gc(vec![0]);
+    collect();
+    let max = raw::heap_size();
+
+    // Dead objects which have not been swept or finalized yet do not count
+    // against the maximum heap size
+    GcConfig::new().defer_finalizers(true).lazy_sweep(1).install();
+    for i in 0..8 {
+        letroot!(temp);
+        temp.gc(vec![i]);
+    }
+    collect();
+    assert!(raw::heap_size() > max);
+    GcConfig::new().defer_finalizers(true).lazy_sweep(1).max_heap_size(max).install();
+    raw::safepoint();
+    assert_eq!(raw::heap_size(), max);
+    assert_eq!(raw::count_pending_finalizers(), 0);
+    assert_eq!(*kept, vec![0]);
+}
+
+#[test]
+fn independent_heaps() {
+    let _ = env_logger::try_init();
+    let heap_a = Heap::new();
+    let heap_b = Heap::new();
+
+    letroot!(root_a in heap_a);
+    letroot!(root_b in heap_b);
+    let a = root_a.gc(PinCell::new(GcStore::new(10)));
+    let b = root_b.gc(PinCell::new(GcStore::new(20)));
+    assert_eq!(heap_a.enter(raw::count_managed_objects), 2);
+    assert_eq!(heap_b.enter(raw::count_managed_objects), 2);
+    assert_eq!(raw::count_managed_objects(), 0);
+
+    // Collecting one heap does not trace or free the other
+    heap_a.enter(|| {
+        letroot!(temp);
+        temp.gc(30);
+    });
+    heap_a.collect();
+    assert_eq!(heap_a.enter(raw::count_managed_objects), 2);
+
+    // Objects from one heap cannot be stored in another
+    let cell_a: &GcPinCell<i32> = unsafe { raw::Store::rooted(&*a) };
+    let cell_b: &GcPinCell<i32> = unsafe { raw::Store::rooted(&*b) };
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        cell_a.set(cell_b.get());
+    }));
+    assert!(result.is_err());
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        letroot!(root in heap_a);
+        root.reroot(b);
+    }));
+    assert!(result.is_err());
+    assert_eq!(*cell_a.get(), 10);
+}
+
+#[test]
+fn sync_heap() {
+    let _ = env_logger::try_init();
+    let heap = SyncHeap::new();
+
+    heap.join(|mutator| {
+        mutator.enter(|| GcConfig::new().stress(true).install());
+        letroot!(root in mutator);
+        let shared = root.gc(0xC0FFEE);
+
+        // Joining the heap again would count this thread twice
+        let nested = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| heap.join(|_| ())));
+        assert!(nested.is_err());
+
+        // Each thread collects at every allocation, stopping the others
+        mutator.blocking(|| std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| heap.join(|mutator| {
+                    for i in 0..100 {
+                        letroot!(temp in mutator);
+                        let x = temp.gc(i);
+                        assert_eq!(*x, i);
+                        assert_eq!(*shared, 0xC0FFEE);
+                    }
+                }));
+            }
+        }));
+
+        mutator.collect();
+        assert_eq!(mutator.enter(raw::count_managed_objects), 1);
+        assert_eq!(*shared, 0xC0FFEE);
+    });
+}
+
+#[test]
+fn parallel_marking() {
+    let _ = env_logger::try_init();
+    GcConfig::new().mark_threads(4).install();
+
+    let tree: Vec<_> = (0..100).map(|i| {
+        GcStore::new((0..100).map(|j| GcStore::new(i * 100 + j)).collect::<Vec<_>>())
+    }).collect();
+
+    letroot!(root);
+    let tree = root.gc(tree);
+    for i in 0..100 {
+        letroot!(temp);
+        temp.gc(i);
+    }
+    assert_eq!(raw::count_managed_objects(), 10_201);
+
+    collect();
+    assert_eq!(raw::count_managed_objects(), 10_101);
+    collect_minor();
+    assert_eq!(raw::count_managed_objects(), 10_101);
+    assert_eq!(tree.len(), 100);
+}
+
+#[test]
+fn concurrent_marking() {
+    let _ = env_logger::try_init();
+    GcConfig::new().concurrent(true).initial_threshold(2048).install();
+
+    letroot!(root);
+    let cells = root.gc((0..16).map(|i| PinCell::new(GcStore::new(i))).collect::<Vec<_>>());
+    let cell = |i: usize| -> &GcPinCell<i32> { unsafe { raw::Store::rooted(&cells[i]) } };
+    let mut expected: Vec<i32> = (0..16).collect();
+
+    // Shuffle and replace the objects in the cells while the marker runs
+    for n in 0..20_000 {
+        let (a, b) = (n % 16, (n * 7 + 3) % 16);
+        let x = cell(a).get();
+        let y = cell(b).get();
+        cell(a).set(y);
+        cell(b).set(x);
+        expected.swap(a, b);
+
+        letroot!(temp);
+        let fresh = temp.gc(n as i32 + 100);
+        cell((n * 5) % 16).set(fresh);
+        expected[(n * 5) % 16] = n as i32 + 100;
+
+        for i in 0..16 {
+            assert_eq!(*cell(i).get(), expected[i]);
+        }
+    }
+
+    // The first collection finishes marking in the background, leaving the
+    // objects which became garbage while it was running
+    collect();
+    collect();
+    assert_eq!(raw::count_managed_objects(), 17);
+}
+
+#[test]
+fn concurrent_marking_of_cells() {
+    use std::cell::Cell;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst};
+
+    type Cells = (PinCell<GcStore<'static, i32>>, Gate, PinCell<GcStore<'static, i32>>);
+
+    static CELLS: AtomicUsize = AtomicUsize::new(0);
+    static SWAPPED: AtomicBool = AtomicBool::new(false);
+    thread_local!(static MUTATOR: Cell<bool> = Cell::new(false));
+
+    // Swaps the stores in the cells on either side of it through borrows,
+    // without a barrier, as the mutator could between the background marker
+    // tracing the one cell and the other
+    struct Gate;
+
+    unsafe impl raw::Trace for Gate {
+        unsafe fn mark(&self) {
+            if !MUTATOR.with(|mutator| mutator.get()) && !SWAPPED.swap(true, SeqCst) {
+                let cells = &*(CELLS.load(SeqCst) as *const Cells);
+                let mut a = Pin::new_unchecked(&cells.0).borrow_mut();
+                let mut b = Pin::new_unchecked(&cells.2).borrow_mut();
+                std::mem::swap(PinMut::as_mut(&mut a).get_unchecked_mut(),
+                               PinMut::as_mut(&mut b).get_unchecked_mut());
+            }
+        }
+        unsafe fn manage(&self) { }
+        unsafe fn finalize(&mut self) { }
+        unsafe fn relocate(&mut self) { }
+    }
+
+    unsafe impl<'root> raw::Reroot<'root> for Gate {
+        type Rerooted = Gate;
+    }
+
+    let _ = env_logger::try_init();
+    GcConfig::new().concurrent(true).initial_threshold(2048).install();
+    MUTATOR.with(|mutator| mutator.set(true));
+
+    letroot!(root);
+    let cells = root.gc((PinCell::new(GcStore::new(0)), Gate, PinCell::new(GcStore::new(1))));
+    CELLS.store(&*cells as *const _ as usize, SeqCst);
+    while !SWAPPED.load(SeqCst) {
+        letroot!(temp);
+        temp.gc(0);
+    }
+
+    collect();
+    collect();
+    assert_eq!(raw::count_managed_objects(), 3);
+    unsafe {
+        assert_eq!(*GcStore::raw(&*cells.0.borrow()).data(), 1);
+        assert_eq!(*GcStore::raw(&*cells.2.borrow()).data(), 0);
+    }
+}
+
+#[test]
+fn lazy_sweeping() {
+    let _ = env_logger::try_init();
+    GcConfig::new().lazy_sweep(4).install();
+
+    letroot!(root);
+    let kept = root.gc(-1);
+    for i in 0..100 {
+        letroot!(temp);
+        temp.gc(i);
+    }
+
+    // Nothing is freed until the heap is swept
+    collect();
+    assert_eq!(raw::count_managed_objects(), 101);
+    assert!(!sweep_step(10));
+    assert_eq!(raw::count_managed_objects(), 91);
+
+    // Each allocation sweeps a few more objects
+    for i in 0..5 {
+        letroot!(temp);
+        temp.gc(i);
+    }
+    assert_eq!(raw::count_managed_objects(), 76);
+
+    // A collection finishes the last sweep before it marks
+    collect();
+    assert_eq!(raw::count_managed_objects(), 6);
+    while !sweep_step(1) { }
+    assert_eq!(raw::count_managed_objects(), 1);
+    assert_eq!(*kept, -1);
+}
+
+#[test]
+fn size_class_reuse() {
+    let _ = env_logger::try_init();
+
+    // An unusual size, so that no other test allocates from the same pages
+    let first = {
+        letroot!(temp);
+        &*temp.gc([[1u64; 8]; 20]) as *const [[u64; 8]; 20] as usize
+    };
+    collect();
+
+    // The freed cell is the first to be reused
+    letroot!(temp);
+    let second = temp.gc([[2u64; 8]; 20]);
+    assert_eq!(&*second as *const [[u64; 8]; 20] as usize, first);
+    assert_eq!(second[19][7], 2);
+}
+
+#[test]
+fn nursery() {
+    let _ = env_logger::try_init();
+    GcConfig::new().initial_threshold(usize::MAX).nursery_size(4096).install();
+
+    letroot!(root);
+    let kept = root.gc(-1);
+    let addr = &*kept as *const i32 as usize;
+    let unmanaged = GcStore::new(-2);
+
+    // Filling the nursery collects only the young generation
+    for i in 0..1000 {
+        letroot!(temp);
+        temp.gc(i);
+    }
+    assert!(raw::count_managed_objects() < 200);
+    assert_eq!(raw::count_managed_objects() - raw::count_young_objects(), 1);
+
+    // Survivors are promoted where they are, and unmanaged objects are untouched
+    assert_eq!(&*kept as *const i32 as usize, addr);
+    assert_eq!(*kept, -1);
+    assert_eq!(*unmanaged.get(), -2);
+
+    // Each heap counts only the young objects which join it
+    collect_minor();
+    let before = raw::count_managed_objects();
+    let heap = Heap::new();
+    heap.enter(|| {
+        GcConfig::new().initial_threshold(usize::MAX).nursery_size(4096).install();
+        for i in 0..100 {
+            letroot!(temp);
+            temp.gc(i);
+        }
+    });
+    for i in 0..100 {
+        letroot!(temp);
+        temp.gc(i);
+    }
+    assert_eq!(raw::count_managed_objects(), before + 100);
+    assert_eq!(heap.enter(raw::count_managed_objects), 100);
+}
+
+#[test]
+fn nursery_holes() {
+    use std::collections::HashSet;
+
+    let _ = env_logger::try_init();
+    GcConfig::new().initial_threshold(usize::MAX).nursery_size(0).install();
+
+    // A few objects survive each round, and the memory freed around them is
+    // allocated again, rather than a fresh page being taken for every round
+    let mut survivors = vec![];
+    let mut pages = HashSet::new();
+    for round in 0..64u64 {
+        for i in 0..4000u64 {
+            letroot!(temp);
+            let object = temp.gc(i);
+            pages.insert(&*object as *const u64 as usize >> 16);
+        }
+        letroot!(temp);
+        survivors.push(HeapRoot::reroot(temp.gc(round)));
+        collect_minor();
+    }
+    assert!(pages.len() < 8, "objects were allocated in {} pages", pages.len());
+    assert_eq!(raw::count_managed_objects(), 64);
+    for (round, survivor) in survivors.iter().enumerate() {
+        assert_eq!(**survivor, round as u64);
+    }
+}
+
+#[test]
+fn large_objects() {
+    let _ = env_logger::try_init();
+    GcConfig::new().large_object_size(1024).install();
+
+    letroot!(root);
+    let stores = root.gc(vec![GcStore::new([[7u64; 16]; 16])]);
+    let large = || unsafe { raw::Store::rooted(&stores[0]) };
+    let size = raw::large_object_space_size();
+    assert!(size > 2048 && size < raw::heap_size());
+
+    // Large objects are kept apart from the young generation
+    assert_eq!(raw::count_young_objects(), 1);
+    {
+        letroot!(temp);
+        temp.gc([[0u64; 16]; 16]);
+        assert_eq!(raw::large_object_space_size(), 2 * size);
+    }
+    collect();
+    assert_eq!(raw::large_object_space_size(), size);
+
+    // Nor are they moved, even when they are neither rooted nor pinned
+    let addr = || unsafe { GcStore::raw(&stores[0]).data() as *const _ as usize };
+    let before = addr();
+    unsafe { raw::compact(); }
+    assert_eq!(addr(), before);
+    assert_eq!(large()[15][15], 7);
+
+    // Each heap decides which of the objects allocated in it are large
+    let heap = Heap::new();
+    letroot!(other in heap);
+    other.gc([[0u64; 16]; 16]);
+    assert_eq!(heap.enter(raw::large_object_space_size), 0);
+}
+
+#[test]
+fn young_objects_in_large_objects() {
+    let _ = env_logger::try_init();
+    GcConfig::new().large_object_size(1024).install();
+
+    letroot!(root);
+    let large = root.gc(([[7u64; 16]; 16], GcStore::new(1), GcStore::new(String::from("young"))));
+    assert_eq!(raw::count_young_objects(), 2);
+
+    // A minor collection traces a new large object, and so the young objects it
+    // points to, before promoting it
+    collect_minor();
+    for i in 0..100 {
+        letroot!(temp);
+        temp.gc(format!("garbage {}", i));
+    }
+    collect_minor();
+    assert_eq!(raw::count_managed_objects(), 3);
+    unsafe {
+        assert_eq!(*GcStore::raw(&large.1).data(), 1);
+        assert_eq!(*GcStore::raw(&large.2).data(), "young");
+    }
+
+    collect();
+    assert_eq!(raw::count_managed_objects(), 3);
+    assert_eq!(large.0[15][15], 7);
+}
+
+#[test]
+fn small_header() {
+    let _ = env_logger::try_init();
+
+    letroot!(root);
+    let before = raw::heap_size();
+    root.gc(0usize);
+
+    // Two words of header and one of data, besides the object's entry in the
+    // heap's tables, which examples/footprint.rs counts
+    assert_eq!(raw::heap_size() - before, 3 * std::mem::size_of::<usize>());
+}
+
+#[test]
+fn leaf_objects() {
+    let _ = env_logger::try_init();
+
+    assert!(<(String, Vec<(u8, i64)>) as raw::Trace>::is_leaf());
+    assert!(<Option<Vec<String>> as raw::Trace>::is_leaf());
+    assert!(!<Vec<GcStore<'_, i32>> as raw::Trace>::is_leaf());
+    assert!(!<PinCell<GcStore<'_, i32>> as raw::Trace>::is_leaf());
+
+    letroot!(root);
+    letroot!(leaf_root);
+    let _branch = root.gc((0..4).map(|i| {
+        GcStore::new((i.to_string(), vec![(i as u8, -(i as i64))]))
+    }).collect::<Vec<_>>());
+    let leaf = leaf_root.gc((String::from("leaf"), vec![(1u8, -1i64)]));
+    collect();
+    collect();
+    assert_eq!(raw::count_managed_objects(), 6);
+    assert_eq!(leaf.0, "leaf");
+    assert_eq!(leaf.1, [(1, -1)]);
+}
+
+#[test]
+fn leaf_objects_are_not_traced() {
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    static TRACED: AtomicUsize = AtomicUsize::new(0);
+
+    // Counts every time the collector calls into it
+    struct Leaf(u32);
+
+    unsafe impl raw::Trace for Leaf {
+        unsafe fn mark(&self) { TRACED.fetch_add(1, SeqCst); }
+        unsafe fn manage(&self) { TRACED.fetch_add(1, SeqCst); }
+        unsafe fn finalize(&mut self) { TRACED.fetch_add(1, SeqCst); }
+        unsafe fn relocate(&mut self) { TRACED.fetch_add(1, SeqCst); }
+        fn is_leaf() -> bool { true }
+    }
+
+    unsafe impl<'root> raw::Reroot<'root> for Leaf {
+        type Rerooted = Leaf;
+    }
+
+    let _ = env_logger::try_init();
+
+    letroot!(root);
+    let kept = root.gc(Leaf(1));
+    for i in 0..8 {
+        letroot!(temp);
+        temp.gc(Leaf(i));
+    }
+    collect_minor();
+    collect();
+    assert_eq!(raw::count_managed_objects(), 1);
+    assert_eq!(kept.0, 1);
+    assert_eq!(TRACED.load(SeqCst), 0);
+}
+
+#[test]
+fn heap_roots() {
+    let _ = env_logger::try_init();
+
+    let first = HeapRoot::new(String::from("first"));
+    let second = HeapRoot::new(String::from("second"));
+    let mut clones: Vec<_> = (0..8).map(|i| match i % 2 {
+        0   => first.clone(),
+        _   => second.clone(),
+    }).collect();
+    assert_eq!(raw::count_roots(), 10);
+
+    // Neither the originals nor their clones are dropped in stack order
+    drop(first);
+    clones.remove(3);
+    clones.remove(0);
+    clones.swap(0, 5);
+    collect();
+    assert_eq!(raw::count_managed_objects(), 2);
+    assert_eq!(*clones[1], "first");
+    assert_eq!(*clones[0], "second");
+
+    // Slots freed out of order are reused
+    let third = HeapRoot::reroot(clones[2].gc());
+    assert_eq!(raw::count_roots(), 8);
+    drop(second);
+    clones.retain(|root| **root != "second");
+    collect();
+    assert_eq!(raw::count_managed_objects(), 1);
+    assert_eq!(raw::count_roots(), 4);
+    assert_eq!(*third.gc(), "first");
+
+    drop(clones);
+    drop(third);
+    collect();
+    assert_eq!(raw::count_managed_objects(), 0);
+    assert_eq!(raw::count_roots(), 0);
+}
+
+#[test]
+fn persistent_heap_roots() {
+    use std::collections::HashMap;
+
+    let _ = env_logger::try_init();
+
+    let mut names: HashMap<String, HeapRoot<String>> = HashMap::new();
+    for name in &["a", "b", "c"] {
+        let value = HeapRoot::new(name.to_uppercase());
+        names.insert(name.to_string(), value);
+    }
+
+    // A HeapRoot held by a managed object is traced instead of rooting
+    let value = names["b"].clone();
+    let holder = HeapRoot::new(vec![value]);
+    assert!(names["b"].is_rooted());
+    assert!(!holder[0].is_rooted());
+    assert_eq!(raw::count_roots(), 4);
+
+    names.remove("b");
+    collect();
+    assert_eq!(raw::count_managed_objects(), 4);
+    assert_eq!(*holder[0].gc(), "B");
+    assert_eq!(*names["c"], "C");
+
+    drop(holder);
+    collect();
+    assert_eq!(raw::count_managed_objects(), 2);
+    assert_eq!(raw::count_roots(), 2);
+
+    drop(names);
+    collect();
+    assert_eq!(raw::count_managed_objects(), 0);
+    assert_eq!(raw::count_roots(), 0);
+
+    // HeapRoots are rooted in the heap which manages their object, wherever
+    // they are made
+    let heap = Heap::new();
+    let holder = heap.enter(|| HeapRoot::new(vec![HeapRoot::new(1i32)]));
+    let rerooted = HeapRoot::reroot(holder.gc());
+    let clone = holder[0].clone();
+    drop(holder);
+    heap.collect();
+    assert_eq!(heap.enter(raw::count_roots), 2);
+    assert_eq!(heap.enter(raw::count_managed_objects), 2);
+    assert_eq!(*rerooted[0].gc(), 1);
+    assert_eq!(*clone, 1);
+    assert_eq!(raw::count_roots(), 0);
+}
+
+#[test]
+fn weak_references() {
+    let _ = env_logger::try_init();
+
+    letroot!(root);
+    let (kept, weak) = {
+        letroot!(kept_root);
+        let kept = HeapRoot::reroot(kept_root.gc(String::from("kept")));
+        letroot!(lost_root);
+        let lost = lost_root.gc(String::from("lost"));
+        let weak = vec![GcWeak::new(kept.gc()), GcWeak::new(lost)];
+        (kept, weak)
+    };
+    // Weak references stored in managed objects do not keep them alive either
+    let weak = root.gc(weak);
+    collect_minor();
+    assert!(!weak[0].is_cleared());
+    assert!(weak[1].is_cleared());
+    assert_eq!(raw::count_managed_objects(), 2);
+
+    unsafe { raw::compact(); }
+    {   letroot!(upgraded);
+        let upgraded = weak[0].upgrade(upgraded).unwrap();
+        assert_eq!(*upgraded, "kept");
+    }
+
+    // An object cannot be upgraded into a root of another heap
+    let heap = Heap::new();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        letroot!(other in heap);
+        weak[0].upgrade(other);
+    }));
+    assert!(result.is_err());
+
+    let copy = weak[0].clone();
+    drop(kept);
+    collect();
+    assert!(weak[0].is_cleared() && copy.is_cleared());
+    letroot!(upgraded);
+    assert!(copy.upgrade(upgraded).is_none());
+    assert_eq!(raw::count_managed_objects(), 1);
+}
+
+#[test]
+fn ephemerons() {
+    let _ = env_logger::try_init();
+
+    let a = HeapRoot::new(String::from("a"));
+    let b = HeapRoot::new(String::from("b"));
+    let c = HeapRoot::new(String::from("c"));
+    let mut map = GcWeakKeyMap::new();
+    // The first value points back to its own key, and the second to the key of
+    // the third, which is reachable only through it
+    map.insert(a.gc(), a.clone());
+    map.insert(b.gc(), c.clone());
+    map.insert(c.gc(), HeapRoot::new(String::from("value of c")));
+    drop(c);
+    assert_eq!(raw::count_roots(), 2);
+
+    collect();
+    assert_eq!(raw::count_managed_objects(), 7);
+    assert_eq!(**map.get(a.gc()).unwrap(), "a");
+    let c = map.get(b.gc()).unwrap();
+    assert_eq!(**map.get(c.gc()).unwrap(), "value of c");
+
+    drop(a);
+    collect();
+    assert_eq!(raw::count_managed_objects(), 5);
+    assert_eq!(map.len(), 2);
+
+    assert!(map.remove(b.gc()));
+    assert!(!map.contains_key(b.gc()));
+    collect_minor();
+    collect();
+    assert_eq!(raw::count_managed_objects(), 1);
+    assert!(map.is_empty());
+
+    // Keys are not pinned, and are found again once compaction has moved them,
+    // though not while they are held by a read through a traced HeapRoot
+    letroot!(root);
+    let holder = root.gc(vec![HeapRoot::new(String::from("moved"))]);
+    let mut map = GcWeakKeyMap::new();
+    map.insert(holder[0].gc(), 1);
+    let before = &*holder[0] as *const String as usize;
+    unsafe { raw::compact(); }
+    assert_eq!(&*holder[0] as *const String as usize, before);
+    collect();
+    unsafe { raw::compact(); }
+    assert_ne!(&*holder[0] as *const String as usize, before);
+    assert_eq!(*map.get(holder[0].gc()).unwrap(), 1);
+
+    // Values are held like keys, only until the end of the next collection
+    let value = map.get(holder[0].gc()).unwrap();
+    let before = &*value as *const i32 as usize;
+    unsafe { raw::compact(); }
+    assert_eq!(&*map.get(holder[0].gc()).unwrap() as *const i32 as usize, before);
+    collect();
+    unsafe { raw::compact(); }
+    assert_ne!(&*map.get(holder[0].gc()).unwrap() as *const i32 as usize, before);
+    assert!(map.remove(holder[0].gc()));
+    assert!(map.is_empty());
+}
+
+#[test]
+fn deferred_finalization() {
+    let _ = env_logger::try_init();
+
+    thread_local! {
+        static FINALIZED: std::cell::RefCell<Vec<i32>> = std::cell::RefCell::new(vec![]);
+    }
+
+    struct Finalized(i32);
+
+    unsafe impl raw::Trace for Finalized {
+        unsafe fn mark(&self) { }
+        unsafe fn manage(&self) { }
+        unsafe fn finalize(&mut self) {
+            // Finalizers may allocate, root objects and collect
+            letroot!(root);
+            let copy = root.gc(self.0);
+            collect();
+            FINALIZED.with(|finalized| finalized.borrow_mut().push(*copy));
+        }
+        unsafe fn relocate(&mut self) { }
+    }
+
+    unsafe impl<'root> raw::Reroot<'root> for Finalized {
+        type Rerooted = Finalized;
+    }
+
+    let finalized = || FINALIZED.with(|finalized| finalized.borrow().len());
+
+    for i in 0..4 {
+        letroot!(root);
+        root.gc(Finalized(i));
+    }
+    collect();
+    assert_eq!(finalized(), 4);
+    assert_eq!(raw::count_pending_finalizers(), 0);
+
+    // Nothing is finalized until the sweep is finished, and then only once
+    // finalizers are run
+    GcConfig::default().defer_finalizers(true).lazy_sweep(2).install();
+    for i in 4..8 {
+        letroot!(root);
+        root.gc(Finalized(i));
+    }
+    collect();
+    assert_eq!(raw::count_pending_finalizers(), 0);
+    assert!(sweep_step(usize::MAX));
+    assert_eq!(raw::count_pending_finalizers(), 4);
+    assert_eq!(finalized(), 4);
+
+    assert_eq!(run_finalizers(), 4);
+    assert_eq!(raw::count_pending_finalizers(), 0);
+    FINALIZED.with(|finalized| {
+        let mut finalized = finalized.borrow().clone();
+        finalized.sort();
+        assert_eq!(finalized, (0..8).collect::<Vec<_>>());
+    });
+}
+
+#[test]
+fn finalization_registry() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let _ = env_logger::try_init();
+
+    let released = Rc::new(RefCell::new(vec![]));
+    let registry = {
+        let released = released.clone();
+        FinalizationRegistry::new(move |handle: usize| released.borrow_mut().push(handle))
+    };
+
+    letroot!(kept_root);
+    let kept = kept_root.gc(String::from("kept"));
+    registry.register(kept, 1);
+    for handle in 2..5 {
+        letroot!(root);
+        registry.register(root.gc(vec![handle]), handle);
+    }
+
+    collect();
+    released.borrow_mut().sort();
+    assert_eq!(*released.borrow(), [2, 3, 4]);
+
+    // Callbacks are not run once the registry has been dropped
+    {   letroot!(root);
+        registry.register(root.gc(()), 5);
+    }
+    drop(registry);
+    collect();
+    assert_eq!(released.borrow().len(), 3);
+    assert_eq!(raw::count_pending_finalizers(), 0);
+    assert_eq!(*kept, "kept");
+
+    // Objects shared between threads cannot be registered
+    let registry = FinalizationRegistry::new(|_: ()| ());
+    SyncHeap::new().join(|mutator| {
+        letroot!(root in mutator);
+        let shared = root.gc(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            registry.register(shared, ());
+        }));
+        assert!(result.is_err());
+    });
+}
+
+#[test]
+fn guardians() {
+    let _ = env_logger::try_init();
+
+    let guardian = Guardian::new();
+    let mut weak = vec![];
+    for i in 0..3 {
+        letroot!(root);
+        let buffer = root.gc(vec![i; 4]);
+        guardian.register(buffer);
+        weak.push(GcWeak::new(buffer));
+    }
+    letroot!(kept_root);
+    let kept = kept_root.gc(vec![9]);
+    guardian.register(kept);
+
+    // Unreachable objects are kept alive until they are handed back
+    collect();
+    collect();
+    assert_eq!(raw::count_managed_objects(), 4);
+    assert!(weak.iter().all(|weak| !weak.is_cleared()));
+
+    let mut polled = vec![];
+    loop {
+        letroot!(root);
+        match guardian.poll(root) {
+            Some(buffer)    => polled.push(buffer[0]),
+            None            => break,
+        }
+    }
+    polled.sort();
+    assert_eq!(polled, [0, 1, 2]);
+
+    // A reclaimed object can be registered again
+    {   letroot!(root);
+        let buffer = weak[0].upgrade(root).unwrap();
+        guardian.register(buffer);
+    }
+    collect();
+    assert_eq!(raw::count_managed_objects(), 2);
+    assert!(weak[1].is_cleared() && weak[2].is_cleared());
+    {   letroot!(root);
+        assert_eq!(guardian.poll(root).map(|buffer| buffer[0]), Some(0));
+    }
+
+    drop(guardian);
+    collect();
+    assert_eq!(raw::count_managed_objects(), 1);
+    assert_eq!(*kept, [9]);
+}
+
+#[test]
+fn soft_references() {
+    let _ = env_logger::try_init();
+
+    GcConfig::default().soft_max_idle(2).install();
+    let (used, unused) = {
+        letroot!(used_root unused_root);
+        let used = used_root.gc(String::from("used"));
+        let unused = unused_root.gc(String::from("unused"));
+        (GcSoft::new(used), GcSoft::new(unused))
+    };
+
+    // Only major collections count towards how long a reference is unused
+    collect();
+    collect_minor();
+    assert!(!used.is_cleared() && !unused.is_cleared());
+    {   letroot!(root);
+        assert_eq!(*used.upgrade(root).unwrap(), "used");
+    }
+    collect();
+    assert!(!used.is_cleared());
+    assert!(unused.is_cleared());
+    assert_eq!(raw::count_managed_objects(), 1);
+
+    // Under memory pressure, every soft reference is given up
+    GcConfig::default().soft_pressure(0).install();
+    collect();
+    assert!(used.is_cleared());
+
+    // A custom policy replaces both limits
+    let kept = {
+        letroot!(root);
+        GcSoft::new(root.gc(String::from("kept")))
+    };
+    GcConfig::default().soft_pressure(0).soft_max_idle(0).soft_policy(|_, _| true).install();
+    collect();
+    assert!(!kept.is_cleared());
+    GcConfig::default().soft_policy(|_, idle| idle < 2).install();
+    collect();
+    assert!(kept.is_cleared());
+    assert_eq!(raw::count_managed_objects(), 0);
+}